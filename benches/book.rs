@@ -0,0 +1,91 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use fast_book::book::book::{OrderId, Orderbook, TimeInForce};
+
+// Whole-book throughput. The sorted-vec levels these replaced are benched against the level
+// table in benches/levels.rs, which runs in this tree; compare those two, not these against
+// numbers from an older checkout.
+
+const ORDERS: usize = 10000;
+
+fn book() -> Orderbook {
+    Orderbook::with_capacities(0, ORDERS * 2)
+}
+
+// resting NO side orders spread over every price, same seed for every run
+fn spread_orders() -> Vec<(u64, i8)> {
+    let mut rng = StdRng::seed_from_u64(9);
+    (0..ORDERS).map(|_| (rng.gen_range(1..10), rng.gen_range(1..100))).collect()
+}
+
+fn populated(orders: &[(u64, i8)]) -> (Orderbook, Vec<OrderId>) {
+    let mut book = book();
+    let oids = orders.iter().map(|(qty, price)| book.add(*qty, *price).unwrap()).collect();
+    (book, oids)
+}
+
+fn bench_add(c: &mut Criterion) {
+    let orders = spread_orders();
+    c.bench_function("add spread", |b| {
+        b.iter_batched(book, |mut book| {
+            for (qty, price) in orders.iter() {
+                black_box(book.add(*qty, *price).unwrap());
+            }
+            book
+        }, BatchSize::LargeInput)
+    });
+    c.bench_function("add crowded level", |b| {
+        b.iter_batched(book, |mut book| {
+            for (qty, _) in orders.iter() {
+                black_box(book.add(*qty, 50).unwrap());
+            }
+            book
+        }, BatchSize::LargeInput)
+    });
+}
+
+fn bench_match(c: &mut Criterion) {
+    let orders = spread_orders();
+    // every fill pushes an EXECUTE and a PRICE, so this also measures growing that Vec
+    c.bench_function("match sweep", |b| {
+        b.iter_batched(|| populated(&orders).0, |mut book| {
+            // small takers walking the book from the top until it is empty
+            for _ in 0..ORDERS {
+                black_box(book.match_order(5, -99, TimeInForce::IOC, false, 0));
+            }
+            book
+        }, BatchSize::LargeInput)
+    });
+    c.bench_function("match against resting", |b| {
+        b.iter_batched(|| populated(&orders).0, |mut book| {
+            // takers that mostly rest because they sit below the book
+            for (qty, price) in orders.iter() {
+                black_box(book.match_order(*qty, -(*price / 2).max(1), TimeInForce::GTC, false, 0));
+            }
+            book
+        }, BatchSize::LargeInput)
+    });
+}
+
+fn bench_cancel(c: &mut Criterion) {
+    let orders = spread_orders();
+    let mut rng = StdRng::seed_from_u64(10);
+    c.bench_function("cancel random order", |b| {
+        b.iter_batched(|| {
+            let (book, mut oids) = populated(&orders);
+            oids.shuffle(&mut rng);
+            (book, oids)
+        }, |(mut book, oids)| {
+            for oid in oids.iter() {
+                black_box(book.delete(*oid).unwrap());
+            }
+            book
+        }, BatchSize::LargeInput)
+    });
+}
+
+criterion_group!(benches, bench_add, bench_match, bench_cancel);
+criterion_main!(benches);
@@ -0,0 +1,202 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+// The level bookkeeping from before and after the price-indexed table, side by side in one tree
+// so the comparison can be rerun anywhere: `cargo bench --bench levels`.
+//
+// SortedLevels is the sorted_yes/sorted_no code the table replaced (scan to insert, position +
+// remove to drop an emptied level, last() for the best price), with its level arena reduced
+// to a Vec and a free list. LevelTable is the table and bitmaps from book.rs. Orders, chains and
+// matching are left out, both sides only track qty per price.
+
+const ORDERS: usize = 10000;
+
+struct PriceLevel {
+    price: i8,
+    level_id: usize,
+}
+
+#[derive(Default)]
+struct SortedLevels {
+    // sorted in asc order
+    sorted_yes: Vec<PriceLevel>,
+    sorted_no: Vec<PriceLevel>,
+    // map[level_id] price, qty
+    levels: Vec<(i8, u64)>,
+    free: Vec<usize>,
+}
+
+impl SortedLevels {
+    fn insert(self: &mut Self, price: i8, qty: u64) -> usize {
+        let sorted_levels = if price < 0 {
+            &mut self.sorted_yes
+        } else {
+            &mut self.sorted_no
+        };
+        let mut insertion_idx: i32 = sorted_levels.len() as i32 - 1;
+        let mut level_id = None;
+
+        for level in sorted_levels.iter().rev() {
+            if level.price == price {
+                level_id = Some(level.level_id);
+                break;
+            }
+            if level.price < price {
+                break;
+            }
+            insertion_idx -= 1;
+        }
+
+        let level_id = match level_id {
+            Some(level_id) => level_id,
+            None => {
+                let level_id = match self.free.pop() {
+                    Some(level_id) => level_id,
+                    None => {
+                        self.levels.push((price, 0));
+                        self.levels.len() - 1
+                    }
+                };
+                self.levels[level_id] = (price, 0);
+                sorted_levels.insert((insertion_idx + 1) as usize, PriceLevel { price: price, level_id: level_id });
+                level_id
+            }
+        };
+        self.levels[level_id].1 += qty;
+        level_id
+    }
+
+    fn reduce(self: &mut Self, level_id: usize, qty: u64) {
+        let level = &mut self.levels[level_id];
+        level.1 -= qty;
+        if level.1 == 0 {
+            let sorted_levels = if level.0 < 0 {
+                &mut self.sorted_yes
+            } else {
+                &mut self.sorted_no
+            };
+            let idx = sorted_levels.iter().rev().position(|x| x.level_id == level_id).unwrap();
+            sorted_levels.remove(sorted_levels.len() - 1 - idx);
+            self.free.push(level_id);
+        }
+    }
+
+    fn best(self: &Self, yes: bool) -> Option<i8> {
+        let sorted_levels = if yes { &self.sorted_yes } else { &self.sorted_no };
+        sorted_levels.last().map(|level| level.price)
+    }
+}
+
+// prices live in -99..=99 so every level gets a fixed slot
+const PRICE_LEVELS: usize = 199;
+
+fn level_index(price: i8) -> usize {
+    (price as i16 + 99) as usize
+}
+
+struct LevelTable {
+    // bit |price| for every negative price with resting qty
+    yes_levels: u128,
+    // bit price for every non-negative price with resting qty
+    no_levels: u128,
+    // map[level_index(price)] qty
+    levels: [u64; PRICE_LEVELS],
+}
+
+impl Default for LevelTable {
+    fn default() -> Self {
+        LevelTable { yes_levels: 0, no_levels: 0, levels: [0; PRICE_LEVELS] }
+    }
+}
+
+impl LevelTable {
+    fn mark(self: &mut Self, price: i8, occupied: bool) {
+        let (mask, bit) = if price < 0 {
+            (&mut self.yes_levels, price.unsigned_abs())
+        } else {
+            (&mut self.no_levels, price as u8)
+        };
+        if occupied {
+            *mask |= 1 << bit;
+        } else {
+            *mask &= !(1 << bit);
+        }
+    }
+
+    fn insert(self: &mut Self, price: i8, qty: u64) -> usize {
+        let level_id = level_index(price);
+        if self.levels[level_id] == 0 {
+            self.mark(price, true);
+        }
+        self.levels[level_id] += qty;
+        level_id
+    }
+
+    fn reduce(self: &mut Self, level_id: usize, qty: u64) {
+        self.levels[level_id] -= qty;
+        if self.levels[level_id] == 0 {
+            self.mark((level_id as i16 - 99) as i8, false);
+        }
+    }
+
+    fn best(self: &Self, yes: bool) -> Option<i8> {
+        if yes {
+            (self.yes_levels != 0).then(|| -(self.yes_levels.trailing_zeros() as i8))
+        } else {
+            (self.no_levels != 0).then(|| (127 - self.no_levels.leading_zeros()) as i8)
+        }
+    }
+}
+
+// the same seed as the book benches, spread over both sides
+fn spread_orders() -> Vec<(u64, i8)> {
+    let mut rng = StdRng::seed_from_u64(9);
+    (0..ORDERS).map(|_| {
+        let price: i8 = rng.gen_range(1..100);
+        (rng.gen_range(1..10), if rng.gen_bool(0.5) { price } else { -price })
+    }).collect()
+}
+
+macro_rules! bench_levels {
+    ($c:expr, $name:literal, $levels:ty) => {{
+        let orders = spread_orders();
+        let mut rng = StdRng::seed_from_u64(10);
+        $c.bench_function(concat!("insert spread ", $name), |b| {
+            b.iter_batched(<$levels>::default, |mut levels| {
+                for (qty, price) in orders.iter() {
+                    black_box(levels.insert(*price, *qty));
+                }
+                levels
+            }, BatchSize::LargeInput)
+        });
+        $c.bench_function(concat!("reduce random ", $name), |b| {
+            b.iter_batched(|| {
+                let mut levels = <$levels>::default();
+                let mut placed: Vec<(usize, u64)> = orders.iter().map(|(qty, price)| (levels.insert(*price, *qty), *qty)).collect();
+                placed.shuffle(&mut rng);
+                (levels, placed)
+            }, |(mut levels, placed)| {
+                // the best price is read after every reduce, like the matching loop does
+                for (level_id, qty) in placed.iter() {
+                    levels.reduce(*level_id, *qty);
+                    black_box(levels.best(true));
+                    black_box(levels.best(false));
+                }
+                levels
+            }, BatchSize::LargeInput)
+        });
+    }};
+}
+
+fn bench_sorted(c: &mut Criterion) {
+    bench_levels!(c, "sorted vecs", SortedLevels);
+}
+
+fn bench_table(c: &mut Criterion) {
+    bench_levels!(c, "level table", LevelTable);
+}
+
+criterion_group!(benches, bench_sorted, bench_table);
+criterion_main!(benches);
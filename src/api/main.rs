@@ -5,6 +5,9 @@ mod ws;
 use actix_web::{get, post, App, HttpResponse, HttpServer, Responder, web};
 use actix_cors::Cors;
 use tokio::sync::broadcast;
+use tokio::time::{interval, Duration};
+
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use fast_book::comm::client::Client;
 
@@ -23,13 +26,26 @@ async fn echo(req_body: String) -> impl Responder {
     HttpResponse::Ok().body(req_body)
 }
 
-const STREAM_ADDR: &'static str = "/tmp/fish.socket";
+const STREAM_ADDR: &str = "/tmp/fish.socket";
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let firebase_auth = firebase_auth::FirebaseAuth::new("bets-fc705").await;
     let (tx, _rx) = broadcast::channel::<String>(100);
-    let client = Client::new(STREAM_ADDR, tx.clone())?;
+    // ENGINE_ADDR points at an engine elsewhere, e.g. tcp://engine:7070
+    let engine_addr = std::env::var("ENGINE_ADDR").unwrap_or(String::from(STREAM_ADDR));
+    let client = Client::new(&engine_addr, tx.clone()).await?;
+
+    // drives the book clocks so GTT orders get expired and refunded
+    let clock_client = client.clone();
+    actix_rt::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            clock_client.expire_orders(now).await;
+        }
+    });
 
 
     HttpServer::new(move || {
@@ -52,8 +68,10 @@ async fn main() -> std::io::Result<()> {
             .service(order::get_orders)
             .service(order::get_orders_satisfied)
             .service(order::create_order)
+            .service(order::create_market_order)
             .service(order::delete_order)
             .service(order::reduce_order)
+            .service(order::amend_order)
             .service(order::set_result)
             .service(order::get_leaderboard)
             .route("/ws/", web::get().to(ws::websocket_route))
@@ -1,7 +1,9 @@
-use fast_book::comm::client::Client;
+use fast_book::comm::client::{Client, ClientError};
+use fast_book::book::book::{OrderId, TimeInForce};
+use fast_book::comm::urcp::AddRequest;
 
 use actix_web::web::Data;
-use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
+use actix_web::{delete, get, patch, post, put, web, HttpResponse, Responder};
 
 use serde::{Deserialize, Serialize};
 
@@ -13,6 +15,13 @@ pub struct CreateOrder {
     price: i8,
     market: u16,
     yes: bool,
+    #[serde(default)]
+    tif: TimeInForce,
+    #[serde(default)]
+    post_only: bool,
+    // unix seconds, only used with GTT
+    #[serde(default)]
+    expires_at: u64,
 }
 
 #[post("/order")]
@@ -21,7 +30,7 @@ pub async fn create_order(
     client: Data<Client>,
     payload: web::Json<CreateOrder>,
 ) -> impl Responder {
-    let user = match client.get_user(user.sub) {
+    let user = match client.get_user(user.sub).await {
         Some(user) => user,
         _ => return HttpResponse::Unauthorized().body("howdy"),
     };
@@ -37,20 +46,64 @@ pub async fn create_order(
         100 - payload.price
     };
 
-    match client.add_order(&user, ip, payload.qty, payload.market) {
-        Some(add_response) => HttpResponse::Ok().json(add_response),
-        None => return HttpResponse::BadRequest().body("bad request not enough schmoney"),
+    let req = AddRequest::new(payload.qty, ip, payload.market, payload.tif, payload.post_only, payload.expires_at);
+
+    match client.add_order(&user, req).await {
+        Ok(add_response) => HttpResponse::Ok().json(add_response),
+        Err(ClientError::InsufficientBalance) => HttpResponse::BadRequest().body("bad request not enough schmoney"),
+        Err(ClientError::WouldCross) => HttpResponse::Conflict().body("post only order would cross"),
+        Err(ClientError::BookFull) => HttpResponse::ServiceUnavailable().body("book is full"),
+        Err(ClientError::Unavailable) => HttpResponse::ServiceUnavailable().body("engine unavailable"),
+        Err(_) => HttpResponse::InternalServerError().body("failure"),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateMarketOrder {
+    qty: u64,
+    // worst price the user is willing to pay
+    limit: i8,
+    market: u16,
+    yes: bool,
+}
+
+#[post("/order/market")]
+pub async fn create_market_order(
+    user: FirebaseUser,
+    client: Data<Client>,
+    payload: web::Json<CreateMarketOrder>,
+) -> impl Responder {
+    let user = match client.get_user(user.sub).await {
+        Some(user) => user,
+        _ => return HttpResponse::Unauthorized().body("howdy"),
+    };
+
+    assert!(payload.market < 2);
+    assert!(payload.limit > 0 && payload.limit < 100);
+    assert!(payload.qty > 0);
+
+    let ip = if payload.yes {
+        -payload.limit
+    } else {
+        100 - payload.limit
+    };
+
+    match client.market_order(&user, ip, payload.qty, payload.market).await {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(ClientError::InsufficientBalance) => HttpResponse::BadRequest().body("bad request not enough schmoney"),
+        Err(ClientError::Unavailable) => HttpResponse::ServiceUnavailable().body("engine unavailable"),
+        Err(_) => HttpResponse::InternalServerError().body("failure"),
     }
 }
 
 #[get("/orders")]
 pub async fn get_orders(user: FirebaseUser, client: Data<Client>) -> impl Responder {
-    let user = match client.get_user(user.sub) {
+    let user = match client.get_user(user.sub).await {
         Some(user) => user,
         _ => return HttpResponse::Unauthorized().body("howdy"),
     };
 
-    match client.get_orders(&user) {
+    match client.get_orders(&user).await {
         Some(orders) => HttpResponse::Ok().json(orders),
         _ => HttpResponse::NotFound().body("not found"),
     }
@@ -58,11 +111,11 @@ pub async fn get_orders(user: FirebaseUser, client: Data<Client>) -> impl Respon
 
 #[get("/orders_satisfied")]
 pub async fn get_orders_satisfied(user: FirebaseUser, client: Data<Client>) -> impl Responder {
-    let user = match client.get_user(user.sub) {
+    let user = match client.get_user(user.sub).await {
         Some(user) => user,
         _ => return HttpResponse::Unauthorized().body("howdy"), };
 
-    match client.get_contracts_for_user(user.id) {
+    match client.get_contracts_for_user(user.id).await {
         Some(orders) => HttpResponse::Ok().json(orders),
         _ => HttpResponse::NotFound().body("not found"),
     }
@@ -72,9 +125,9 @@ pub async fn get_orders_satisfied(user: FirebaseUser, client: Data<Client>) -> i
 pub async fn delete_order(
     user: FirebaseUser,
     client: Data<Client>,
-    payload: web::Path<(usize, u16)>,
+    payload: web::Path<(OrderId, u16)>,
 ) -> impl Responder {
-    let user = match client.get_user(user.sub) {
+    let user = match client.get_user(user.sub).await {
         Some(user) => user,
         _ => return HttpResponse::Unauthorized().body("howdy"),
     };
@@ -83,16 +136,18 @@ pub async fn delete_order(
 
     assert!(market < 2);
 
-    match client.cancel_order(&user, oid, market) {
-        Some(_) => HttpResponse::Ok().body("success"),
-        None => HttpResponse::InternalServerError().body("failure"),
+    match client.cancel_order(&user, oid, market).await {
+        Ok(()) => HttpResponse::Ok().body("success"),
+        Err(ClientError::NotFound) => HttpResponse::NotFound().body("not found"),
+        Err(ClientError::Unavailable) => HttpResponse::ServiceUnavailable().body("engine unavailable"),
+        Err(_) => HttpResponse::InternalServerError().body("failure"),
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ModifyOrder {
     qty: u64,
-    oid: usize,
+    oid: OrderId,
     market: u16,
 }
 
@@ -102,14 +157,58 @@ pub async fn reduce_order(
     client: Data<Client>,
     payload: web::Json<ModifyOrder>,
 ) -> impl Responder {
-    let user = match client.get_user(user.sub) {
+    let user = match client.get_user(user.sub).await {
         Some(user) => user,
         _ => return HttpResponse::Unauthorized().body("howdy"),
     };
 
-    match client.reduce_order(&user, payload.oid, payload.qty, payload.market) {
-        Some(_) => HttpResponse::Ok().body("success"),
-        None => HttpResponse::InternalServerError().body("failure"),
+    match client.reduce_order(&user, payload.oid, payload.qty, payload.market).await {
+        Ok(()) => HttpResponse::Ok().body("success"),
+        Err(ClientError::NotFound) => HttpResponse::NotFound().body("not found"),
+        Err(ClientError::Unavailable) => HttpResponse::ServiceUnavailable().body("engine unavailable"),
+        Err(_) => HttpResponse::InternalServerError().body("failure"),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AmendOrder {
+    oid: OrderId,
+    market: u16,
+    // new total size and price, same conventions as CreateOrder
+    qty: u64,
+    price: i8,
+    yes: bool,
+}
+
+#[patch("/order")]
+pub async fn amend_order(
+    user: FirebaseUser,
+    client: Data<Client>,
+    payload: web::Json<AmendOrder>,
+) -> impl Responder {
+    let user = match client.get_user(user.sub).await {
+        Some(user) => user,
+        _ => return HttpResponse::Unauthorized().body("howdy"),
+    };
+
+    assert!(payload.market < 2);
+    assert!(payload.price > 0 && payload.price < 100);
+    assert!(payload.qty > 0);
+
+    let ip = if payload.yes {
+        -payload.price
+    } else {
+        100 - payload.price
+    };
+
+    match client.amend_order(&user, payload.oid, payload.market, payload.qty, ip).await {
+        Ok(amend_response) => HttpResponse::Ok().json(amend_response),
+        Err(ClientError::InsufficientBalance) => HttpResponse::BadRequest().body("bad request not enough schmoney"),
+        Err(ClientError::NotFound) => HttpResponse::NotFound().body("not found"),
+        Err(ClientError::WouldCross) => HttpResponse::Conflict().body("post only order would cross"),
+        Err(ClientError::BookFull) => HttpResponse::ServiceUnavailable().body("book is full"),
+        Err(ClientError::Unavailable) => HttpResponse::ServiceUnavailable().body("engine unavailable"),
+        Err(_) => HttpResponse::InternalServerError().body("failure"),
     }
 }
 
@@ -120,12 +219,12 @@ pub async fn set_result(client: Data<Client>, payload: web::Path<usize>) -> impl
     let top: bool = bit_repr & 2 > 0;
     let right: bool = bit_repr & 1 > 0;
 
-    client.flush_exchange(top, right);
+    client.flush_exchange(top, right).await;
 
     HttpResponse::Ok().body("success")
 }
 
 #[get("/leaderboard")]
 pub async fn get_leaderboard(client: Data<Client>) -> impl Responder {
-    HttpResponse::Ok().json(client.get_leaderboard().unwrap())
+    HttpResponse::Ok().json(client.get_leaderboard().await.unwrap())
 }
@@ -8,7 +8,7 @@ use firebase_auth::FirebaseUser;
 
 #[post("/user")]
 pub async fn create_user(user: FirebaseUser, client: Data<Client>) -> impl Responder {
-    match client.create_user(user.sub) {
+    match client.create_user(user.sub).await {
         Some(_) => HttpResponse::Ok().json(GenericResponse{msg: "ok".to_string()}),
         None => HttpResponse::BadRequest().json(GenericResponse{msg: "err".to_string()})
     }
@@ -16,7 +16,7 @@ pub async fn create_user(user: FirebaseUser, client: Data<Client>) -> impl Respo
 
 #[get("/user")]
 pub async fn get_user(user: FirebaseUser, client: Data<Client>) -> impl Responder {
-    match client.get_user(user.sub) {
+    match client.get_user(user.sub).await {
         Some(user) => HttpResponse::Ok().json(user),
         None => HttpResponse::NotFound().json(GenericResponse{msg: "err".to_string()})
     }
@@ -1,3 +1,6 @@
+// FTXWS and its match-based ping handling predate the lint config
+#![allow(clippy::upper_case_acronyms, clippy::single_match)]
+
 use actix::{Actor, StreamHandler, AsyncContext, spawn};
 use actix_web::{web, HttpResponse, HttpRequest, Error};
 use actix_web_actors::ws;
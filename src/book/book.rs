@@ -1,14 +1,142 @@
-use crate::book::bump::BumpAllocator;
+// the match-based Option plumbing and trailing return here predate the lint config
+#![allow(clippy::manual_map, clippy::needless_return)]
+
 use crate::book::pool::{BasicArena, MemArena};
 use crate::comm::urcp::*;
-use std::cell::RefCell;
+use serde::{Deserialize, Serialize};
 use std::cmp;
-use std::rc::Rc;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::fmt;
+use std::io::{self, Read};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BookError {
+    // oid was never handed out by the arena
+    UnknownOrder = b'U',
+    // order was taken out by a match
+    AlreadyFilled = b'F',
+    // order was taken out by a cancel or a reduce to zero
+    AlreadyCancelled = b'C',
+    // reduce asked for more than the order has left
+    ReduceExceedsRemaining = b'R',
+    // oid belongs to a different book
+    WrongBook = b'W',
+    // every order slot is live, nothing new can rest until something leaves
+    BookFull = b'B',
+    // oid's slot has since been handed to a newer order
+    StaleOrder = b'S',
+}
+
+impl BookError {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            b'U' => Some(BookError::UnknownOrder),
+            b'F' => Some(BookError::AlreadyFilled),
+            b'C' => Some(BookError::AlreadyCancelled),
+            b'R' => Some(BookError::ReduceExceedsRemaining),
+            b'W' => Some(BookError::WrongBook),
+            b'B' => Some(BookError::BookFull),
+            b'S' => Some(BookError::StaleOrder),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BookError::UnknownOrder => f.write_str("unknown order"),
+            BookError::AlreadyFilled => f.write_str("order already filled"),
+            BookError::AlreadyCancelled => f.write_str("order already cancelled"),
+            BookError::ReduceExceedsRemaining => f.write_str("reduce exceeds remaining quantity"),
+            BookError::WrongBook => f.write_str("order belongs to another book"),
+            BookError::BookFull => f.write_str("book is full"),
+            BookError::StaleOrder => f.write_str("order id is stale"),
+        }
+    }
+}
+
+impl std::error::Error for BookError {}
+
+fn corrupt(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad book snapshot: {}", what))
+}
+
+// the next N bytes of a snapshot
+fn take<const N: usize>(buf: &mut &[u8]) -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    buf.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn take_u32(buf: &mut &[u8]) -> io::Result<usize> {
+    Ok(u32::from_le_bytes(take(buf)?) as usize)
+}
+
+fn take_u64(buf: &mut &[u8]) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(take(buf)?))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[repr(u8)]
+pub enum OrderStatus {
+    Resting = b'R',
+    PartiallyFilled = b'P',
+    Filled = b'F',
+    Cancelled = b'C',
+}
+
+impl OrderStatus {
+    pub fn is_live(&self) -> bool {
+        matches!(self, OrderStatus::Resting | OrderStatus::PartiallyFilled)
+    }
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            b'R' => Some(OrderStatus::Resting),
+            b'P' => Some(OrderStatus::PartiallyFilled),
+            b'F' => Some(OrderStatus::Filled),
+            b'C' => Some(OrderStatus::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+// what happens to the part of an incoming order that doesn't cross
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum TimeInForce {
+    // rest the remainder on the book
+    #[default]
+    GTC = b'G',
+    // fill what crosses, kill the remainder
+    IOC = b'I',
+    // fill everything or touch nothing
+    FOK = b'F',
+    // rest the remainder until the book clock passes its expiry
+    GTT = b'T',
+}
+
+impl TimeInForce {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            b'G' => Some(TimeInForce::GTC),
+            b'I' => Some(TimeInForce::IOC),
+            b'F' => Some(TimeInForce::FOK),
+            b'T' => Some(TimeInForce::GTT),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct OrderChain {
     qty: u64,
-    level_id: usize,
+    status: OrderStatus,
+    expires_at: u64, // 0 when the order never expires
+    post_only: bool, // kept through amends, a replacement may not cross either
+    level_id: usize, // index into the price level table
     next: usize, // this pointer should be in the bump arena
     prev: usize,
 }
@@ -17,6 +145,9 @@ impl OrderChain {
     fn new(qty: u64) -> Self {
         OrderChain {
             qty: qty,
+            status: OrderStatus::Resting,
+            expires_at: 0,
+            post_only: false,
             level_id: usize::MAX,
             next: usize::MAX,
             prev: usize::MAX,
@@ -26,6 +157,7 @@ impl OrderChain {
 
 pub struct Level {
     pub head: usize, // this pointer should be in the bump arena
+    pub tail: usize, // last order in the chain so appends don't walk it
     price: i8,
     qty: u64,
 }
@@ -34,16 +166,57 @@ impl Level {
     pub fn new(price: i8, qty: u64) -> Self {
         Level {
             head: usize::MAX,
+            tail: usize::MAX,
             price: price,
             qty: qty,
         }
     }
 }
 
-#[derive(Debug)]
-pub struct PriceLevel {
-    price: i8,
-    level_id: usize,
+// oids handed out of the book: the book id in the top 16 bits, the slot's generation below
+// that and the arena slot in the low 32, so oids are unique across books. a slot is retired
+// once it has been through every generation that fits, so an oid is never handed out twice.
+// chains and levels link raw slots, anything that leaves the book carries an OrderId
+pub type OrderId = u64;
+
+const LAST_GENERATION: u32 = u16::MAX as u32;
+
+fn make_oid(book: u16, slot: usize, generation: u32) -> OrderId {
+    debug_assert!(generation <= LAST_GENERATION);
+    (book as u64) << 48 | (generation as u64) << 32 | slot as u64
+}
+
+fn oid_slot(oid: OrderId) -> usize {
+    (oid & 0xffff_ffff) as usize
+}
+
+fn oid_generation(oid: OrderId) -> u16 {
+    (oid >> 32) as u16
+}
+
+fn oid_book(oid: OrderId) -> u16 {
+    (oid >> 48) as u16
+}
+
+// prices live in -99..=99 so every level gets a fixed slot
+const PRICE_LEVELS: usize = 199;
+
+fn level_index(price: i8) -> usize {
+    (price as i16 + 99) as usize
+}
+
+// flips the level's bit in its side's bitmap
+fn mark_level(yes_levels: &mut u128, no_levels: &mut u128, price: i8, occupied: bool) {
+    let (mask, bit) = if price < 0 {
+        (yes_levels, price.unsigned_abs())
+    } else {
+        (no_levels, price as u8)
+    };
+    if occupied {
+        *mask |= 1 << bit;
+    } else {
+        *mask &= !(1 << bit);
+    }
 }
 
 pub struct Orderbook {
@@ -52,195 +225,286 @@ pub struct Orderbook {
     //
     // Really at the end of the day forming a contract is just a matter of agreeing on a yes price
 
-    // These sorted vecs should be small enough to perma be in cache; sorted in asc order
+    // Non-empty levels as bitmaps so best price is a single bit scan
     //
-    // sorted {price, qty, chain}
-    sorted_yes: Vec<PriceLevel>,
-    // sorted {price, qty, chain}
-    sorted_no: Vec<PriceLevel>,
+    // bit |price| for every negative price with resting qty
+    yes_levels: u128,
+    // bit price for every non-negative price with resting qty
+    no_levels: u128,
 
     // map[oid] Order
-    order_arena: Rc<RefCell<BumpAllocator<OrderChain>>>,
-    // map[level_id] price, qty, chain
-    level_arena: BasicArena<Level>,
+    order_arena: BasicArena<OrderChain>,
+    // map[level_index(price)] price, qty, chain
+    levels: Vec<Level>,
+
+    // index of this book in the manager; stamped on every order it creates
+    id: u16,
+
+    // latest time handed to advance_clock; only ever moves forward
+    clock: u64,
+    // min-heap of (expires_at, oid) for GTT orders, may hold orders that already left the book
+    expiries: BinaryHeap<Reverse<(u64, OrderId)>>,
 }
 
 impl Orderbook {
     pub fn clear(&mut self) {
-        self.sorted_yes.clear();
-        self.sorted_no.clear();
-        self.order_arena.borrow_mut().clear();
-        self.level_arena.clear();
+        // slots keep their orders until they're reused, as cancelled ones
+        for price in self.side_prices(true).chain(self.side_prices(false)).collect::<Vec<i8>>() {
+            let mut slot = self.levels[level_index(price)].head;
+            while slot != usize::MAX {
+                let order = &mut self.order_arena[slot];
+                order.status = OrderStatus::Cancelled;
+                order.qty = 0;
+                slot = order.next;
+            }
+        }
+        self.yes_levels = 0;
+        self.no_levels = 0;
+        self.order_arena.clear();
+        for level in self.levels.iter_mut() {
+            *level = Level::new(level.price, 0);
+        }
+        self.expiries.clear();
     }
 
     pub fn with_capacities(
-        order_arena: Rc<RefCell<BumpAllocator<OrderChain>>>,
-        level_capacity: usize,
+        id: u16,
+        order_capacity: usize,
     ) -> Self {
         Orderbook {
-            sorted_no: Vec::new(),
-            sorted_yes: Vec::new(),
-            order_arena: order_arena,
-            level_arena: BasicArena::with_capacity(level_capacity),
+            yes_levels: 0,
+            no_levels: 0,
+            order_arena: BasicArena::with_generations(order_capacity, LAST_GENERATION),
+            levels: (0..PRICE_LEVELS).map(|i| Level::new((i as i16 - 99) as i8, 0)).collect(),
+            id: id,
+            clock: 0,
+            expiries: BinaryHeap::new(),
         }
     }
-    fn insert_order(self: &mut Self, order_id: usize, price: i8) -> (i8, i64) {
-        let mut order_arena = self.order_arena.borrow_mut();
-        let order = order_arena.get(order_id);
-        let sorted_levels = if price < 0 {
-            &mut self.sorted_yes
-        } else {
-            &mut self.sorted_no
-        };
-        let mut insertion_idx: i32 = sorted_levels.len() as i32 - 1;
-        let mut found = false;
-
-        for level in sorted_levels.iter().rev() {
-            if level.price == price {
-                order.level_id = level.level_id;
-                found = true;
-                break;
-            }
-            if level.price < price {
-                break;
+    pub fn id(self: &Self) -> u16 {
+        self.id
+    }
+    // prices with resting qty on one side, best first
+    //
+    // yes levels go from the smallest |price| out, no levels from the largest price down
+    fn side_prices(self: &Self, yes: bool) -> impl Iterator<Item = i8> {
+        let mut mask = if yes { self.yes_levels } else { self.no_levels };
+        std::iter::from_fn(move || {
+            if mask == 0 {
+                return None;
             }
-            insertion_idx -= 1;
-        }
-
-        if !found {
-            let level_idx = self.level_arena.alloc();
-            order.level_id = level_idx;
-            self.level_arena.set(level_idx, Level::new(price, 0));
-            insertion_idx += 1;
-            sorted_levels.insert(
-                insertion_idx as usize,
-                PriceLevel {
-                    price: price,
-                    level_id: level_idx,
-                },
-            );
-        }
+            let bit = if yes {
+                mask.trailing_zeros()
+            } else {
+                127 - mask.leading_zeros()
+            };
+            mask &= !(1 << bit);
+            Some(if yes { -(bit as i8) } else { bit as i8 })
+        })
+    }
+    fn insert_order(self: &mut Self, order_id: usize, price: i8) -> (i8, i64) {
+        let order_arena = &mut self.order_arena;
+        let order = &mut order_arena[order_id];
+        let level_idx = level_index(price);
+        order.level_id = level_idx;
 
-        let level = &mut self.level_arena[order.level_id];
+        let level = &mut self.levels[level_idx];
+        let was_empty = level.qty == 0;
         level.qty += order.qty;
+        let ret = (level.price, order.qty as i64);
 
-        (level.price, order.qty as i64)
+        if was_empty {
+            mark_level(&mut self.yes_levels, &mut self.no_levels, price, true);
+        }
+
+        ret
     }
     fn add_to_order_chain(self: &mut Self, order_id: usize) {
-        let mut order_arena = self.order_arena.borrow_mut();
-        let order = order_arena.get(order_id);
-        let level = &mut self.level_arena[order.level_id];
+        let order_arena = &mut self.order_arena;
+        let order = &mut order_arena[order_id];
+        let level = &mut self.levels[order.level_id];
 
         if level.head == usize::MAX {
             level.head = order_id;
         } else {
-            let mut cur_order_idx = level.head;
-            let mut next_order_idx = order_arena.get(level.head).next;
-            while next_order_idx != usize::MAX {
-                cur_order_idx = next_order_idx;
-                next_order_idx = order_arena.get(cur_order_idx).next;
-            }
-            order_arena.get(cur_order_idx).next = order_id;
-            order_arena.get(order_id).prev = cur_order_idx;
+            order_arena[level.tail].next = order_id;
+            order_arena[order_id].prev = level.tail;
         }
+        level.tail = order_id;
     }
-    fn reduce_order(self: &mut Self, order_id: usize, mut qty: u64) -> PriceLevelResponse {
-        let mut order_arena = self.order_arena.borrow_mut();
-        let order = order_arena.get(order_id);
-        // debug_assert!(order.qty >= qty);
-        // qty = cmp::min(order.qty, qty); // prevents underflow errors in case of user tardation
-        let level = &mut self.level_arena[order.level_id];
+    // `done` is the status the order ends up in if this takes it to zero;
+    // a fill that leaves something behind marks the order partially filled
+    fn reduce_order(self: &mut Self, order_id: usize, qty: u64, done: OrderStatus) -> PriceLevelResponse {
+        let order_arena = &mut self.order_arena;
+        let order = &mut order_arena[order_id];
+        // callers go through check_order first; anything else is a bug in the book
+        debug_assert!(order.status.is_live());
+        debug_assert!(order.qty >= qty);
+        let level = &mut self.levels[order.level_id];
         level.qty -= qty;
         order.qty -= qty;
-        
+
+        if order.qty == 0 {
+            order.status = done;
+        } else if done == OrderStatus::Filled {
+            order.status = OrderStatus::PartiallyFilled;
+        }
+
         let ret = PriceLevelResponse {
             price: level.price,
             delta: -(qty as i64),
         };
+        let gone = order.qty == 0;
 
         if level.qty == 0 {
-            let sorted_levels: &mut Vec<PriceLevel> = if level.price < 0 {
-                &mut self.sorted_yes
-            } else {
-                &mut self.sorted_no
-            };
-            let idx = sorted_levels
-                .iter()
-                .rev()
-                .position(|x| x.level_id == order.level_id);
-            debug_assert!(idx.is_some());
-            sorted_levels.remove(sorted_levels.len() - 1 - idx.unwrap());
-            self.level_arena.free(order.level_id);
+            // last order out, the slot goes back to empty
+            let price = level.price;
+            level.head = usize::MAX;
+            level.tail = usize::MAX;
+            mark_level(&mut self.yes_levels, &mut self.no_levels, price, false);
         } else if order.qty == 0 {
             // we don't have to do this if we lose the level because references to these order will
             // be lost
-            let prev = order_arena.get(order_id).prev;
-            let next = order_arena.get(order_id).next;
+            let prev = order_arena[order_id].prev;
+            let next = order_arena[order_id].next;
             if prev == usize::MAX {
                 level.head = next;
-                order_arena.get(next).prev = usize::MAX;
+                order_arena[next].prev = usize::MAX;
                 // if prev is NULL and tail is the same as prev
                 // this must be the only order at the level
                 // which can't be because the above branch didn't execute
                 // thus we only have to modify head
             } else {
-                // prev is not null, we don't care if next is null
-                // unlink the order
-                order_arena.get(prev).next = next;
+                // prev is not null, unlink the order
+                // and pull the tail back if it was the last one
+                order_arena[prev].next = next;
                 if next != usize::MAX {
-                    order_arena.get(next).prev = prev;
+                    order_arena[next].prev = prev;
+                } else {
+                    level.tail = prev;
                 }
             }
         }
 
+        // the slot keeps its final status for lookups until the arena hands it out again
+        if gone {
+            order_arena.free(order_id);
+        }
+
         ret
     }
-    pub fn delete(self: &mut Self, order_id: usize) -> PriceLevelResponse {
-        let order_qty = self.order_arena.borrow_mut().get(order_id).qty;
-        self.reduce_order(order_id, order_qty)
+    // the oid the order in `slot` is known by outside the book
+    fn oid_of(self: &Self, slot: usize) -> OrderId {
+        make_oid(self.id, slot, self.order_arena.generation(slot))
     }
-    pub fn reduce(self: &mut Self, order_id: usize, qty: u64) -> PriceLevelResponse {
-        self.reduce_order(order_id, qty)
+    // maps an oid coming from outside the book back to its slot
+    fn resolve(self: &Self, order_id: OrderId) -> Result<usize, BookError> {
+        if oid_book(order_id) != self.id {
+            return Err(BookError::WrongBook);
+        }
+        let slot = oid_slot(order_id);
+        if self.order_arena.get(slot).is_none() {
+            return Err(BookError::UnknownOrder);
+        }
+        if self.order_arena.generation(slot) != oid_generation(order_id) as u32 {
+            return Err(BookError::StaleOrder);
+        }
+        Ok(slot)
     }
-    pub fn add(self: &mut Self, qty: u64, price: i8) -> usize {
-        let order_id = self.order_arena.borrow_mut().write(OrderChain::new(qty));
-        self.insert_order(order_id, price);
-        self.add_to_order_chain(order_id);
-        order_id
+    // validates an oid coming from outside the book, returns its slot and the qty still resting
+    fn check_order(self: &Self, order_id: OrderId) -> Result<(usize, u64), BookError> {
+        let slot = self.resolve(order_id)?;
+        let order = &self.order_arena[slot];
+        match order.status {
+            OrderStatus::Filled => Err(BookError::AlreadyFilled),
+            OrderStatus::Cancelled => Err(BookError::AlreadyCancelled),
+            _ => Ok((slot, order.qty)),
+        }
+    }
+    pub fn order_status(self: &Self, order_id: OrderId) -> Result<StatusResponse, BookError> {
+        let slot = self.resolve(order_id)?;
+        let order = &self.order_arena[slot];
+        Ok(StatusResponse::new(order_id, order.status, order.qty))
+    }
+    // every order still resting, by level best first and in queue order within one
+    pub fn live_orders(self: &Self) -> Vec<StatusResponse> {
+        let mut ret = Vec::new();
+        for price in self.side_prices(true).chain(self.side_prices(false)) {
+            let mut slot = self.levels[level_index(price)].head;
+            while slot != usize::MAX {
+                let order = &self.order_arena[slot];
+                ret.push(StatusResponse::new(self.oid_of(slot), order.status, order.qty));
+                slot = order.next;
+            }
+        }
+        ret
+    }
+    pub fn delete(self: &mut Self, order_id: OrderId) -> Result<PriceLevelResponse, BookError> {
+        let (slot, order_qty) = self.check_order(order_id)?;
+        Ok(self.reduce_order(slot, order_qty, OrderStatus::Cancelled))
+    }
+    pub fn reduce(self: &mut Self, order_id: OrderId, qty: u64) -> Result<PriceLevelResponse, BookError> {
+        let (slot, order_qty) = self.check_order(order_id)?;
+        if qty > order_qty {
+            return Err(BookError::ReduceExceedsRemaining);
+        }
+        Ok(self.reduce_order(slot, qty, OrderStatus::Cancelled))
+    }
+    // rests `qty` at `price` without matching it, BookFull when every slot is live
+    pub fn add(self: &mut Self, qty: u64, price: i8) -> Result<OrderId, BookError> {
+        let slot = self.order_arena.alloc(OrderChain::new(qty)).ok_or(BookError::BookFull)?;
+        self.insert_order(slot, price);
+        self.add_to_order_chain(slot);
+        Ok(self.oid_of(slot))
     }
     fn best_order(self: &Self, price: i8) -> Option<(usize, i8)> {
         // get the best level for a particular price
         // doesn't guarantee a match just checks price sign for getting the order
 
-        let sorted_levels = if price < 0 {
-            &self.sorted_no
-        } else {
-            &self.sorted_yes
-        };
-        let price_level = sorted_levels.last();
-
-        match price_level {
+        match self.side_prices(price >= 0).next() {
             None => None,
-            Some(price_level) => Some({
-                let level = &self.level_arena[price_level.level_id];
+            Some(best) => Some({
+                let level = &self.levels[level_index(best)];
                 (level.head, level.price)
             }),
         }
     }
-    pub fn match_order(self: &mut Self, mut qty: u64, price: i8) -> Vec<OBResponseWrapper> {
-        let mut actions: Vec<OBResponseWrapper> = Vec::new();
+    // qty resting on the other side that an order at `price` would take, stopping at `limit`
+    //
+    // walks the levels in the same order match_order consumes them
+    fn crossing_qty(self: &Self, price: i8, limit: u64) -> u64 {
+        let mut available = 0;
+        for level_price in self.side_prices(price >= 0) {
+            if level_price.abs() > price.abs() || available >= limit {
+                break;
+            }
+            available += self.levels[level_index(level_price)].qty;
+        }
+        available
+    }
+    // takes resting orders that cross `price` until `qty` is used up
+    //
+    // returns the qty filled and what it cost summed over the resting prices
+    fn sweep(self: &mut Self, mut qty: u64, price: i8, actions: &mut Vec<OBResponseWrapper>) -> (u64, u64) {
+        let mut filled = 0;
+        let mut notional = 0;
 
         while let Some((lh, lp)) = self.best_order(price) {
-            let head_qty = self.order_arena.borrow_mut().get(lh).qty;
+            if qty == 0 {
+                break;
+            }
+            let head_qty = self.order_arena[lh].qty;
             if lp.abs() <= price.abs() {
                 // TODO: fix this line and we're gtg i think
                 let transaction_qty = cmp::min(qty, head_qty);
-                let price_delta = self.reduce_order(lh, transaction_qty);
+                let price_delta = self.reduce_order(lh, transaction_qty, OrderStatus::Filled);
                 qty -= transaction_qty;
+                filled += transaction_qty;
+                notional += transaction_qty * lp.unsigned_abs() as u64;
 
                 actions.push(OBResponseWrapper {
                     resp: OBResponse {
-                        execute: ExecuteResponse::new(lh, transaction_qty),
+                        execute: ExecuteResponse::new(self.oid_of(lh), transaction_qty),
                     },
                     typ: OBRespType::EXECUTE,
                 });
@@ -251,14 +515,73 @@ impl Orderbook {
             } else {
                 break;
             }
+        }
 
-            if qty == 0 {
-                break;
-            }
+        (filled, notional)
+    }
+    // `expires_at` is only looked at for GTT orders
+    pub fn match_order(self: &mut Self, mut qty: u64, price: i8, tif: TimeInForce, post_only: bool, expires_at: u64) -> Vec<OBResponseWrapper> {
+        let mut actions: Vec<OBResponseWrapper> = Vec::new();
+
+        // makers only; refuse the whole order rather than take liquidity
+        if post_only && self.crossing_qty(price, 1) > 0 {
+            actions.push(OBResponseWrapper {
+                resp: OBResponse {
+                    reject: RejectResponse::new(qty),
+                },
+                typ: OBRespType::REJECT,
+            });
+            return actions;
         }
 
-        if qty > 0 {
-            let oid = self.add(qty, price);
+        if tif == TimeInForce::FOK && self.crossing_qty(price, qty) < qty {
+            actions.push(OBResponseWrapper {
+                resp: OBResponse {
+                    kill: KillResponse::new(qty),
+                },
+                typ: OBRespType::KILL,
+            });
+            return actions;
+        }
+
+        // GTT orders that are already past their expiry behave like IOC
+        let rests = match tif {
+            TimeInForce::GTC => true,
+            TimeInForce::GTT => expires_at > self.clock,
+            _ => false,
+        };
+
+        // a remainder with nowhere to rest refuses the order before anything fills. a sweep
+        // that leaves a remainder took every crossing order whole, so one that crosses
+        // anything at all frees a slot for it
+        if rests && self.order_arena.is_full() && self.crossing_qty(price, 1) == 0 {
+            actions.push(OBResponseWrapper {
+                resp: OBResponse {
+                    error: ErrorResponse::new(BookError::BookFull),
+                },
+                typ: OBRespType::ERROR,
+            });
+            return actions;
+        }
+
+        let (filled, _) = self.sweep(qty, price, &mut actions);
+        qty -= filled;
+
+        if qty > 0 && !rests {
+            actions.push(OBResponseWrapper {
+                resp: OBResponse {
+                    kill: KillResponse::new(qty),
+                },
+                typ: OBRespType::KILL,
+            });
+        } else if qty > 0 {
+            // there's a slot, checked before the sweep
+            let oid = self.add(qty, price).expect("no slot for the remainder");
+            self.order_arena[oid_slot(oid)].post_only = post_only;
+            if tif == TimeInForce::GTT {
+                self.order_arena[oid_slot(oid)].expires_at = expires_at;
+                self.expiries.push(Reverse((expires_at, oid)));
+            }
             actions.push(OBResponseWrapper {
                 resp: OBResponse {
                     add: AddResponse::new(oid, qty),
@@ -276,94 +599,322 @@ impl Orderbook {
         actions
     }
 
-    pub fn get_level_view(self: &Self) -> [u64; 200] {
-        let mut ret: [u64; 200] = [0; 200];
-        for pl in &self.sorted_yes {
-            let level_id = pl.level_id;
-            let (qty, _) = {
-                let level = &self.level_arena[level_id];
-                (level.qty, level.head)
-            };
+    // replaces a resting order in one step
+    //
+    // same price with the size going down reduces in place and keeps queue priority;
+    // anything else pulls the order and sends it back through match_order, keeping any GTT expiry
+    // and post only. the AMEND response always leads, followed by the cancel delta and whatever
+    // the re-match produced. a post only order that would cross at the new price gets a lone
+    // REJECT instead and stays as it was
+    pub fn amend(self: &mut Self, order_id: OrderId, qty: u64, price: i8) -> Result<Vec<OBResponseWrapper>, BookError> {
+        let (slot, remaining) = self.check_order(order_id)?;
+        let (cur_price, expires_at, post_only) = {
+            let order_arena = &self.order_arena;
+            let order = &order_arena[slot];
+            (self.levels[order.level_id].price, order.expires_at, order.post_only)
+        };
+        let mut actions: Vec<OBResponseWrapper> = Vec::new();
+
+        if price == cur_price && qty <= remaining {
+            actions.push(OBResponseWrapper {
+                resp: OBResponse {
+                    amend: AmendResponse::new(order_id, order_id, qty, remaining - qty),
+                },
+                typ: OBRespType::AMEND,
+            });
+            if qty < remaining {
+                let price_delta = self.reduce_order(slot, remaining - qty, OrderStatus::Cancelled);
+                actions.push(OBResponseWrapper {
+                    resp: OBResponse { price: price_delta },
+                    typ: OBRespType::PRICE,
+                });
+            }
+            return Ok(actions);
+        }
+
+        // checked before the old order is pulled, the same check match_order makes
+        if post_only && self.crossing_qty(price, 1) > 0 {
+            actions.push(OBResponseWrapper {
+                resp: OBResponse {
+                    reject: RejectResponse::new(qty),
+                },
+                typ: OBRespType::REJECT,
+            });
+            return Ok(actions);
+        }
+
+        let price_delta = self.reduce_order(slot, remaining, OrderStatus::Cancelled);
+        let tif = if expires_at != 0 {
+            TimeInForce::GTT
+        } else {
+            TimeInForce::GTC
+        };
+        let mut matched = self.match_order(qty, price, tif, post_only, expires_at);
+
+        let (new_oid, resting) = matched
+            .iter()
+            .find_map(|x| match x.typ {
+                OBRespType::ADD => Some(unsafe { (x.resp.add.oid, x.resp.add.qty) }),
+                _ => None,
+            })
+            .unwrap_or((OrderId::MAX, 0));
+
+        actions.push(OBResponseWrapper {
+            resp: OBResponse {
+                amend: AmendResponse::new(order_id, new_oid, resting, remaining),
+            },
+            typ: OBRespType::AMEND,
+        });
+        actions.push(OBResponseWrapper {
+            resp: OBResponse { price: price_delta },
+            typ: OBRespType::PRICE,
+        });
+        actions.append(&mut matched);
+
+        Ok(actions)
+    }
+
+    // sweeps the other side down to `limit`, the worst price the taker accepts,
+    // and never rests; ends with a MARKET summary and a KILL for anything unfilled
+    pub fn market_order(self: &mut Self, qty: u64, limit: i8) -> Vec<OBResponseWrapper> {
+        let mut actions: Vec<OBResponseWrapper> = Vec::new();
+
+        let (filled, notional) = self.sweep(qty, limit, &mut actions);
 
-            ret[level_id] = qty;
+        if filled < qty {
+            actions.push(OBResponseWrapper {
+                resp: OBResponse {
+                    kill: KillResponse::new(qty - filled),
+                },
+                typ: OBRespType::KILL,
+            });
         }
+        actions.push(OBResponseWrapper {
+            resp: OBResponse {
+                market: MarketResponse::from_fills(filled, notional),
+            },
+            typ: OBRespType::MARKET,
+        });
+
+        actions
+    }
 
-        for pl in &self.sorted_no {
-            let level_id = pl.level_id;
-            let (qty, _) = {
-                let level = &self.level_arena[level_id];
-                (level.qty, level.head)
+    // moves the book clock to `now` and cancels every GTT order whose expiry has passed,
+    // each one reported as EXPIRED followed by the same PRICE delta a cancel produces
+    pub fn advance_clock(self: &mut Self, now: u64) -> Vec<OBResponseWrapper> {
+        let mut actions: Vec<OBResponseWrapper> = Vec::new();
+        self.clock = cmp::max(self.clock, now);
+
+        while let Some(&Reverse((expires_at, oid))) = self.expiries.peek() {
+            if expires_at > self.clock {
+                break;
+            }
+            self.expiries.pop();
+
+            // filled or cancelled before it ran out, possibly with the slot reused since
+            let (slot, qty) = match self.check_order(oid) {
+                Ok(found) => found,
+                Err(_) => continue,
             };
+            if self.order_arena[slot].expires_at != expires_at {
+                continue;
+            }
 
-            ret[level_id + 100] = qty;
+            let price_delta = self.reduce_order(slot, qty, OrderStatus::Cancelled);
+            actions.push(OBResponseWrapper {
+                resp: OBResponse {
+                    expired: ExpiredResponse::new(oid, qty),
+                },
+                typ: OBRespType::EXPIRED,
+            });
+            actions.push(OBResponseWrapper {
+                resp: OBResponse { price: price_delta },
+                typ: OBRespType::PRICE,
+            });
+        }
+
+        actions
+    }
+
+    pub fn get_level_view(self: &Self) -> [u64; 200] {
+        let mut ret: [u64; 200] = [0; 200];
+        for price in self.side_prices(true) {
+            ret[price.unsigned_abs() as usize] = self.levels[level_index(price)].qty;
+        }
+
+        for price in self.side_prices(false) {
+            ret[price as usize + 100] = self.levels[level_index(price)].qty;
         }
 
         return ret;
     }
 
     pub fn print(self: &Self) {
-        let mut order_arena = self.order_arena.borrow_mut();
-        println!("YES");
-        for pl in self.sorted_yes.iter().rev() {
-            let level_id = pl.level_id;
-            let (qty, head) = {
-                let level = &self.level_arena[level_id];
-                (level.qty, level.head)
-            };
-            println!("| $0.{} @ {}", pl.price.abs(), qty);
+        let order_arena = &self.order_arena;
+        for (name, yes) in [("YES", true), ("NO", false)] {
+            println!("{}", name);
+            for price in self.side_prices(yes) {
+                let (qty, head) = {
+                    let level = &self.levels[level_index(price)];
+                    (level.qty, level.head)
+                };
+                println!("| $0.{} @ {}", price.abs(), qty);
+
+                let mut cur_idx = head;
+
+                while cur_idx != usize::MAX {
+                    let order = &order_arena[cur_idx];
+                    println!("|---- #{} @ {}", cur_idx, order.qty);
+                    cur_idx = order.next;
+                }
+            }
+        }
+    }
 
-            let mut cur_idx = head;
+    // Point-in-time copy of the book, restore() puts it back exactly
+    //
+    // LE like URCP: id u16 and clock u64; the arena's generations (u32 count, u32 each), its
+    // slots (u32 count, then status u8, qty u64, expires_at u64 and post_only u8 each) and its free list
+    // (u32 count, u32 slots, next to be reused first); every level with qty (u8 count, then
+    // price i8 and its queue as a u32 count of u32 slots, head first); the expiry heap (u32
+    // count, then expires_at u64 and oid u64 each). links and level totals come from the queues
+    pub fn snapshot(self: &Self, out: &mut Vec<u8>) {
+        let arena = &self.order_arena;
+        out.extend_from_slice(&self.id.to_le_bytes());
+        out.extend_from_slice(&self.clock.to_le_bytes());
 
-            while cur_idx != usize::MAX {
-                let order = order_arena.get(cur_idx);
-                println!("|---- #{} @ {}", cur_idx, order.qty);
-                cur_idx = order.next;
+        out.extend_from_slice(&(arena.generations().len() as u32).to_le_bytes());
+        for generation in arena.generations() {
+            out.extend_from_slice(&generation.to_le_bytes());
+        }
+        out.extend_from_slice(&(arena.slots().len() as u32).to_le_bytes());
+        for order in arena.slots() {
+            out.push(order.status as u8);
+            out.extend_from_slice(&order.qty.to_le_bytes());
+            out.extend_from_slice(&order.expires_at.to_le_bytes());
+            out.push(order.post_only as u8);
+        }
+        out.extend_from_slice(&(arena.free_slots().count() as u32).to_le_bytes());
+        for slot in arena.free_slots() {
+            out.extend_from_slice(&(slot as u32).to_le_bytes());
+        }
+
+        let prices: Vec<i8> = self.side_prices(true).chain(self.side_prices(false)).collect();
+        out.push(prices.len() as u8);
+        for price in prices {
+            let mut queue = Vec::new();
+            let mut slot = self.levels[level_index(price)].head;
+            while slot != usize::MAX {
+                queue.push(slot);
+                slot = arena[slot].next;
+            }
+            out.push(price as u8);
+            out.extend_from_slice(&(queue.len() as u32).to_le_bytes());
+            for slot in queue {
+                out.extend_from_slice(&(slot as u32).to_le_bytes());
             }
         }
 
-        println!("NO");
-        for pl in self.sorted_no.iter().rev() {
-            let level_id = pl.level_id;
-            let (qty, head) = {
-                let level = &self.level_arena[level_id];
-                (level.qty, level.head)
+        out.extend_from_slice(&(self.expiries.len() as u32).to_le_bytes());
+        for Reverse((expires_at, oid)) in self.expiries.iter() {
+            out.extend_from_slice(&expires_at.to_le_bytes());
+            out.extend_from_slice(&oid.to_le_bytes());
+        }
+    }
+
+    // reads one book's snapshot off the front of `buf`. anything that couldn't have come from
+    // a book is refused, e.g. a live order missing from its level or a slot past capacity
+    pub fn restore(buf: &mut &[u8], order_capacity: usize) -> io::Result<Self> {
+        let id = u16::from_le_bytes(take(buf)?);
+        let clock = take_u64(buf)?;
+
+        let generations = (0..take_u32(buf)?).map(|_| Ok(take_u32(buf)? as u32)).collect::<io::Result<Vec<u32>>>()?;
+        let mut slots = Vec::new();
+        for _ in 0..take_u32(buf)? {
+            let status = OrderStatus::from_u8(take::<1>(buf)?[0]).ok_or_else(|| corrupt("order status"))?;
+            let mut order = OrderChain::new(take_u64(buf)?);
+            order.status = status;
+            order.expires_at = take_u64(buf)?;
+            order.post_only = match take::<1>(buf)?[0] {
+                0 => false,
+                1 => true,
+                _ => return Err(corrupt("post only flag")),
             };
-            println!("| $0.{} @ {}", pl.price.abs(), qty);
+            slots.push(order);
+        }
+        if slots.len() > order_capacity || generations.len() != slots.len() || generations.iter().any(|g| *g > LAST_GENERATION) {
+            return Err(corrupt("arena doesn't fit the book"));
+        }
+
+        // every slot is either free or queued on exactly one level
+        let mut placed = vec![false; slots.len()];
+        let mut place = |slot: usize, live: bool| match slots.get(slot) {
+            Some(order) if !placed[slot] && order.status.is_live() == live => {
+                placed[slot] = true;
+                Ok(slot)
+            },
+            _ => Err(corrupt("slot out of place")),
+        };
+        let free = (0..take_u32(buf)?).map(|_| place(take_u32(buf)?, false)).collect::<io::Result<VecDeque<usize>>>()?;
+        let mut queues = Vec::new();
+        for _ in 0..take::<1>(buf)?[0] {
+            let price = take::<1>(buf)?[0] as i8;
+            if !(-99..=99).contains(&price) {
+                return Err(corrupt("price out of range"));
+            }
+            let queue = (0..take_u32(buf)?).map(|_| place(take_u32(buf)?, true)).collect::<io::Result<Vec<usize>>>()?;
+            queues.push((price, queue));
+        }
+        // only a retired slot is neither, with nothing resting; one on its last generation is never free
+        if (0..slots.len()).any(|slot| !placed[slot] && (generations[slot] != LAST_GENERATION || slots[slot].status.is_live()))
+            || free.iter().any(|slot| generations[*slot] == LAST_GENERATION) {
+            return Err(corrupt("slot out of place"));
+        }
+        let live = queues.iter().map(|(_, queue)| queue.len()).sum();
 
-            let mut cur_idx = head;
+        let mut expiries = BinaryHeap::new();
+        for _ in 0..take_u32(buf)? {
+            let expires_at = take_u64(buf)?;
+            expiries.push(Reverse((expires_at, take_u64(buf)?)));
+        }
 
-            while cur_idx != usize::MAX {
-                let order = order_arena.get(cur_idx);
-                println!("|---- #{} @ {}", cur_idx, order.qty);
-                cur_idx = order.next;
+        let mut book = Orderbook::with_capacities(id, order_capacity);
+        book.clock = clock;
+        book.expiries = expiries;
+        book.order_arena = BasicArena::restore(order_capacity, LAST_GENERATION, slots, generations, free, live);
+        for (price, queue) in queues {
+            if queue.is_empty() || book.levels[level_index(price)].qty > 0 {
+                return Err(corrupt("level out of place"));
+            }
+            for slot in queue {
+                book.insert_order(slot, price);
+                book.add_to_order_chain(slot);
             }
         }
+        Ok(book)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    fn arena() -> Rc<RefCell<BumpAllocator<OrderChain>>> {
-        Rc::new(RefCell::new(BumpAllocator::<OrderChain>::with_capacity(100)))
-    }
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
     fn book() -> Orderbook {
-        let arena = arena();
-        Orderbook::with_capacities(arena, 200)
+        Orderbook::with_capacities(0, 100)
     }
 
     fn test_n_remove(n: usize, idx: usize) {
         let mut book = book();
         let mut order_vec: Vec<usize> = vec![];
         for i in 0..n {
-            book.add((i + 1) as u64, 50);
+            book.add((i + 1) as u64, 50).unwrap();
             order_vec.push(i);
         }
 
         // delete the targeted order
-        book.reduce_order(idx, (idx + 1) as u64);
+        book.reduce_order(idx, (idx + 1) as u64, OrderStatus::Cancelled);
         order_vec.remove(idx);
         book.print();
 
@@ -372,7 +923,7 @@ mod tests {
         match order_vec.len() {
             0 => {
                 assert!(best_order.is_none());
-                assert!(book.level_arena.len() == 0);
+                assert!(book.no_levels == 0);
                 return;
             },
             _ => assert!(best_order.is_some())
@@ -384,11 +935,11 @@ mod tests {
         // asser that the best order id is the one we put in first (after removal)
         assert!(best_oid == *order_vec.first().unwrap());
 
-        let mut arena = book.order_arena.borrow_mut();
+        let arena = &book.order_arena;
         let mut oid_iter = best_oid;
         let mut oid_prev = usize::MAX;
         for e in order_vec.iter() {
-            let order = arena.get(oid_iter);
+            let order = &arena[oid_iter];
             assert!(oid_iter == *e);
             assert!(order.qty == (*e + 1) as u64);
             assert!(order.prev == oid_prev, "{}.prev is incorrectly {} not {}", oid_iter, order.prev, oid_prev);
@@ -399,25 +950,142 @@ mod tests {
 
         assert!(oid_iter == usize::MAX, "last order in chain should be nil");
 
-        let best_order = arena.get(best_oid);
+        let best_order = &arena[best_oid];
 
-        let best_level = &book.level_arena[best_order.level_id];
+        let best_level = &book.levels[best_order.level_id];
 
         assert!(best_level.head == *order_vec.first().unwrap());
+        assert!(best_level.tail == *order_vec.last().unwrap());
+    }
+
+    // oids at `price` from head to tail, found by walking next pointers like appends used to
+    fn walk_chain(book: &Orderbook, price: i8) -> Vec<OrderId> {
+        let level = &book.levels[level_index(price)];
+        if level.qty == 0 {
+            assert!(level.head == usize::MAX && level.tail == usize::MAX);
+            assert!(!book.side_prices(price < 0).any(|x| x == price));
+            return vec![];
+        }
+        assert!(book.side_prices(price < 0).any(|x| x == price));
+        let arena = &book.order_arena;
+
+        let mut chain = vec![];
+        let mut prev = usize::MAX;
+        let mut cur = level.head;
+        while cur != usize::MAX {
+            let order = &arena[cur];
+            assert!(order.prev == prev, "{}.prev is {} not {}", cur, order.prev, prev);
+            chain.push(make_oid(book.id, cur, arena.generation(cur)));
+            prev = cur;
+            cur = order.next;
+        }
+        assert!(level.tail == prev, "tail is {} but the chain ends at {}", level.tail, prev);
+        chain
+    }
+
+    #[test]
+    fn test_side_prices_best_first() {
+        let mut book = book();
+        for price in [-1, -99, -50, 0, 99, 50, 1] {
+            book.add(1, price).unwrap();
+        }
+        // same order the sorted vecs used to be walked in, back to front
+        assert!(book.side_prices(true).collect::<Vec<i8>>() == vec![-1, -50, -99]);
+        assert!(book.side_prices(false).collect::<Vec<i8>>() == vec![99, 50, 1, 0]);
+        assert!(book.best_order(10).unwrap().1 == -1);
+        assert!(book.best_order(-10).unwrap().1 == 99);
+
+        let view = book.get_level_view();
+        assert!(view[1] == 1 && view[99] == 1 && view[100] == 1 && view[199] == 1);
+        assert!(view.iter().sum::<u64>() == 7);
+    }
+
+    #[test]
+    fn test_level_view_by_price() {
+        let mut book = Orderbook::with_capacities(0, 10);
+        let a = book.add(5, -30).unwrap();
+        book.add(2, -30).unwrap();
+        let b = book.add(4, 70).unwrap();
+        book.add(3, 0).unwrap();
+
+        let view = book.get_level_view();
+        assert!(view[30] == 7 && view[170] == 4 && view[100] == 3);
+        assert!(view.iter().sum::<u64>() == 14);
+
+        book.reduce(a, 1).unwrap();
+        book.delete(b).unwrap();
+        let view = book.get_level_view();
+        assert!(view[30] == 6 && view[170] == 0 && view[100] == 3);
+        assert!(view.iter().sum::<u64>() == 9);
+    }
+
+    #[test]
+    fn test_tail_matches_chain_walk() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let prices: [i8; 3] = [48, 49, 50];
+        let mut book = Orderbook::with_capacities(0, 10000);
+        // per price fifo of (oid, qty), what the chains should look like
+        let mut model: Vec<Vec<(OrderId, u64)>> = vec![vec![]; prices.len()];
+
+        for _ in 0..2000 {
+            match rng.gen_range(0..4) {
+                0 | 1 => {
+                    let p = rng.gen_range(0..prices.len());
+                    let qty = rng.gen_range(1..10);
+                    let oid = book.add(qty, prices[p]).unwrap();
+                    model[p].push((oid, qty));
+                },
+                2 => {
+                    let p = rng.gen_range(0..prices.len());
+                    if model[p].is_empty() {
+                        continue;
+                    }
+                    let i = rng.gen_range(0..model[p].len());
+                    let (oid, qty) = model[p][i];
+                    let cut = rng.gen_range(1..=qty);
+                    book.reduce(oid, cut).unwrap();
+                    if cut == qty {
+                        model[p].remove(i);
+                    } else {
+                        model[p][i].1 -= cut;
+                    }
+                },
+                _ => {
+                    // sweeps from the highest price down, fifo inside a level
+                    let mut qty = rng.gen_range(1..20);
+                    book.market_order(qty, -99);
+                    for p in (0..prices.len()).rev() {
+                        while qty > 0 && !model[p].is_empty() {
+                            let take = cmp::min(qty, model[p][0].1);
+                            qty -= take;
+                            model[p][0].1 -= take;
+                            if model[p][0].1 == 0 {
+                                model[p].remove(0);
+                            }
+                        }
+                    }
+                },
+            }
+
+            for (p, price) in prices.iter().enumerate() {
+                let expected: Vec<OrderId> = model[p].iter().map(|x| x.0).collect();
+                assert!(walk_chain(&book, *price) == expected);
+            }
+        }
     }
 
     #[test]
     fn test_chaining() {
         let mut book = book();
         for i in 1..101 {
-            book.add(i, 99);
+            book.add(i, 99).unwrap();
         }
 
-        let mut arena = book.order_arena.borrow_mut();
+        let arena = &book.order_arena;
         let mut oid = 0;
         // oid's should be 0 -> 99
         for i in 0..100 {
-            let order = arena.get(oid);
+            let order = &arena[oid];
             assert!(order.qty == i + 1);
             assert!(oid == i as usize);
             oid = order.next;
@@ -453,4 +1121,607 @@ mod tests {
     fn test_2_remove_last() {
         test_n_remove(2, 1);
     }
+
+    #[test]
+    fn test_unknown_order() {
+        let mut book = book();
+        book.add(10, 50).unwrap();
+        assert!(matches!(book.delete(1), Err(BookError::UnknownOrder)));
+        assert!(matches!(book.reduce(1, 1), Err(BookError::UnknownOrder)));
+        assert!(matches!(book.delete(make_oid(0, u32::MAX as usize, 0)), Err(BookError::UnknownOrder)));
+        // the top bits name a book that isn't this one
+        assert!(matches!(book.delete(OrderId::MAX), Err(BookError::WrongBook)));
+    }
+
+    #[test]
+    fn test_reduce_exceeds_remaining() {
+        let mut book = book();
+        let oid = book.add(10, 50).unwrap();
+        assert!(matches!(book.reduce(oid, 11), Err(BookError::ReduceExceedsRemaining)));
+
+        // the failed reduce must leave the book untouched
+        let resp = book.reduce(oid, 4).unwrap();
+        assert!(resp.price == 50 && resp.delta == -4);
+        assert!(book.check_order(oid) == Ok((oid_slot(oid), 6)));
+    }
+
+    #[test]
+    fn test_delete_twice() {
+        let mut book = book();
+        let oid = book.add(10, 50).unwrap();
+        book.add(5, 50).unwrap();
+        assert!(book.delete(oid).unwrap().delta == -10);
+        assert!(matches!(book.delete(oid), Err(BookError::AlreadyCancelled)));
+        assert!(matches!(book.reduce(oid, 1), Err(BookError::AlreadyCancelled)));
+    }
+
+    #[test]
+    fn test_filled_order() {
+        let mut book = book();
+        let oid = book.add(10, 50).unwrap();
+        book.match_order(10, -50, TimeInForce::GTC, false, 0);
+        assert!(matches!(book.delete(oid), Err(BookError::AlreadyFilled)));
+    }
+
+    fn killed_qty(actions: &[OBResponseWrapper]) -> Option<u64> {
+        actions.iter().find_map(|x| match x.typ {
+            OBRespType::KILL => Some(unsafe { x.resp.kill.qty }),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_ioc_kills_remainder() {
+        let mut book = book();
+        let oid = book.add(6, 50).unwrap();
+        let actions = book.match_order(10, -50, TimeInForce::IOC, false, 0);
+
+        assert!(killed_qty(&actions) == Some(4));
+        assert!(!actions.iter().any(|x| matches!(x.typ, OBRespType::ADD)));
+        assert!(book.order_status(oid).unwrap().status == OrderStatus::Filled);
+        assert!(book.best_order(50).is_none());
+    }
+
+    #[test]
+    fn test_ioc_without_cross() {
+        let mut book = book();
+        book.add(6, 60).unwrap();
+        let actions = book.match_order(10, -50, TimeInForce::IOC, false, 0);
+        assert!(actions.len() == 1);
+        assert!(killed_qty(&actions) == Some(10));
+        assert!(book.best_order(60).is_none());
+    }
+
+    #[test]
+    fn test_fok_kill_leaves_book_untouched() {
+        let mut book = book();
+        let first = book.add(3, 49).unwrap();
+        let second = book.add(3, 50).unwrap();
+        let actions = book.match_order(7, -50, TimeInForce::FOK, false, 0);
+
+        assert!(actions.len() == 1);
+        assert!(killed_qty(&actions) == Some(7));
+        assert!(book.order_status(first).unwrap().qty == 3);
+        assert!(book.order_status(second).unwrap().qty == 3);
+    }
+
+    #[test]
+    fn test_post_only_rejects_cross() {
+        let mut book = book();
+        let oid = book.add(5, 50).unwrap();
+        let actions = book.match_order(3, -50, TimeInForce::GTC, true, 0);
+
+        assert!(actions.len() == 1);
+        assert!(matches!(actions[0].typ, OBRespType::REJECT));
+        assert!(unsafe { actions[0].resp.reject.qty } == 3);
+        assert!(book.order_status(oid).unwrap().qty == 5);
+        assert!(book.best_order(50).is_none());
+    }
+
+    #[test]
+    fn test_post_only_rests() {
+        let mut book = book();
+        book.add(5, 50).unwrap();
+        let actions = book.match_order(3, -40, TimeInForce::GTC, true, 0);
+
+        assert!(matches!(actions[0].typ, OBRespType::ADD));
+        let oid = unsafe { actions[0].resp.add.oid };
+        assert!(book.order_status(oid).unwrap().status == OrderStatus::Resting);
+    }
+
+    fn market_summary(actions: &[OBResponseWrapper]) -> MarketResponse {
+        let last = actions.last().unwrap();
+        assert!(matches!(last.typ, OBRespType::MARKET));
+        unsafe { last.resp.market }
+    }
+
+    #[test]
+    fn test_market_sweeps_levels() {
+        let mut book = book();
+        book.add(2, 40).unwrap();
+        book.add(3, 50).unwrap();
+        let actions = book.market_order(4, -99);
+
+        let summary = market_summary(&actions);
+        assert!(summary.filled == 4 && summary.notional == 3 * 50 + 40);
+        assert!(summary.avg_price == 47.5);
+        assert!(killed_qty(&actions).is_none());
+        assert!(book.crossing_qty(-99, u64::MAX) == 1);
+    }
+
+    #[test]
+    fn test_market_stops_at_limit() {
+        let mut book = book();
+        book.add(3, 50).unwrap();
+        let actions = book.market_order(10, -45);
+
+        let summary = market_summary(&actions);
+        assert!(summary.filled == 0 && summary.avg_price == 0.0);
+        assert!(killed_qty(&actions) == Some(10));
+        // never rests
+        assert!(book.best_order(45).is_none());
+        assert!(book.crossing_qty(-99, u64::MAX) == 3);
+    }
+
+    #[test]
+    fn test_market_kills_unfilled() {
+        let mut book = book();
+        book.add(2, 40).unwrap();
+        let actions = book.market_order(5, -99);
+
+        assert!(market_summary(&actions).filled == 2);
+        assert!(killed_qty(&actions) == Some(3));
+        assert!(book.best_order(99).is_none());
+    }
+
+    fn expired_oids(actions: &[OBResponseWrapper]) -> Vec<OrderId> {
+        actions.iter().filter_map(|x| match x.typ {
+            OBRespType::EXPIRED => Some(unsafe { x.resp.expired.oid }),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn test_gtt_expires() {
+        let mut book = book();
+        let actions = book.match_order(5, 50, TimeInForce::GTT, false, 10);
+        let oid = unsafe { actions[0].resp.add.oid };
+        let other = book.add(3, 50).unwrap();
+
+        assert!(book.advance_clock(9).is_empty());
+
+        let actions = book.advance_clock(10);
+        assert!(expired_oids(&actions) == vec![oid]);
+        let delta = unsafe { actions[1].resp.price };
+        assert!(delta.price == 50 && delta.delta == -5);
+        assert!(book.order_status(oid).unwrap().status == OrderStatus::Cancelled);
+        assert!(book.order_status(other).unwrap().qty == 3);
+
+        // time only moves forward and nothing expires twice
+        assert!(book.advance_clock(5).is_empty());
+        assert!(book.advance_clock(20).is_empty());
+    }
+
+    #[test]
+    fn test_gtt_skips_dead_orders() {
+        let mut book = book();
+        let actions = book.match_order(5, 50, TimeInForce::GTT, false, 10);
+        let filled = unsafe { actions[0].resp.add.oid };
+        let actions = book.match_order(5, 50, TimeInForce::GTT, false, 10);
+        let cancelled = unsafe { actions[0].resp.add.oid };
+        let actions = book.match_order(5, 50, TimeInForce::GTT, false, 12);
+        let live = unsafe { actions[0].resp.add.oid };
+
+        book.match_order(5, -50, TimeInForce::GTC, false, 0);
+        book.delete(cancelled).unwrap();
+        assert!(book.order_status(filled).unwrap().status == OrderStatus::Filled);
+
+        assert!(book.advance_clock(11).is_empty());
+        assert!(expired_oids(&book.advance_clock(12)) == vec![live]);
+        assert!(book.best_order(-50).is_none());
+    }
+
+    #[test]
+    fn test_gtt_already_expired() {
+        let mut book = book();
+        book.advance_clock(10);
+        let actions = book.match_order(5, 50, TimeInForce::GTT, false, 10);
+        assert!(actions.len() == 1);
+        assert!(killed_qty(&actions) == Some(5));
+        assert!(book.best_order(-50).is_none());
+    }
+
+    fn amend_summary(actions: &[OBResponseWrapper]) -> AmendResponse {
+        assert!(matches!(actions[0].typ, OBRespType::AMEND));
+        unsafe { actions[0].resp.amend }
+    }
+
+    #[test]
+    fn test_amend_down_keeps_priority() {
+        let mut book = book();
+        let first = book.add(5, 50).unwrap();
+        let second = book.add(5, 50).unwrap();
+        let actions = book.amend(first, 2, 50).unwrap();
+
+        let summary = amend_summary(&actions);
+        assert!(summary.new_oid == first && summary.qty == 2);
+        let delta = unsafe { actions[1].resp.price };
+        assert!(delta.price == 50 && delta.delta == -3);
+
+        // still ahead of the second order
+        assert!(book.best_order(-50).unwrap().0 == oid_slot(first));
+        book.match_order(3, -50, TimeInForce::GTC, false, 0);
+        assert!(book.order_status(first).unwrap().status == OrderStatus::Filled);
+        assert!(book.order_status(second).unwrap().qty == 4);
+    }
+
+    #[test]
+    fn test_amend_up_requeues() {
+        let mut book = book();
+        let first = book.add(5, 50).unwrap();
+        let second = book.add(5, 50).unwrap();
+        let actions = book.amend(first, 6, 50).unwrap();
+
+        let summary = amend_summary(&actions);
+        assert!(summary.new_oid != first && summary.qty == 6);
+        assert!(book.order_status(first).unwrap().status == OrderStatus::Cancelled);
+        assert!(book.best_order(-50).unwrap().0 == oid_slot(second));
+        assert!(book.crossing_qty(-50, u64::MAX) == 11);
+    }
+
+    #[test]
+    fn test_amend_price_matches() {
+        let mut book = book();
+        let resting = book.add(3, 40).unwrap();
+        let actions = book.match_order(5, -30, TimeInForce::GTC, false, 0);
+        let oid = unsafe { actions[0].resp.add.oid };
+
+        // moving the bid up to 40 crosses the resting order
+        let actions = book.amend(oid, 5, -40).unwrap();
+        let summary = amend_summary(&actions);
+        assert!(summary.qty == 2);
+        assert!(actions.iter().any(|x| matches!(x.typ, OBRespType::EXECUTE)));
+        assert!(book.order_status(resting).unwrap().status == OrderStatus::Filled);
+        assert!(book.order_status(summary.new_oid).unwrap().qty == 2);
+    }
+
+    #[test]
+    fn test_amend_dead_order() {
+        let mut book = book();
+        let oid = book.add(5, 50).unwrap();
+        book.delete(oid).unwrap();
+        assert!(matches!(book.amend(oid, 5, 40), Err(BookError::AlreadyCancelled)));
+    }
+
+    #[test]
+    fn test_amend_keeps_post_only() {
+        let mut book = book();
+        let resting = book.add(3, 40).unwrap();
+        let actions = book.match_order(5, -30, TimeInForce::GTC, true, 0);
+        let oid = unsafe { actions[0].resp.add.oid };
+
+        // moving the bid up to 40 would take the resting order, so nothing happens
+        let actions = book.amend(oid, 5, -40).unwrap();
+        assert!(actions.len() == 1 && matches!(actions[0].typ, OBRespType::REJECT));
+        assert!(book.order_status(oid).unwrap().qty == 5);
+        assert!(book.order_status(resting).unwrap().qty == 3);
+
+        // a move that doesn't cross replaces it, still post only
+        let summary = amend_summary(&book.amend(oid, 6, -35).unwrap());
+        assert!(summary.qty == 6 && summary.pulled == 5);
+        let actions = book.amend(summary.new_oid, 6, -45).unwrap();
+        assert!(matches!(actions[0].typ, OBRespType::REJECT));
+        assert!(book.order_status(resting).unwrap().qty == 3);
+    }
+
+    #[test]
+    fn test_fok_fills_across_levels() {
+        let mut book = book();
+        let first = book.add(3, 49).unwrap();
+        let second = book.add(4, 50).unwrap();
+        let actions = book.match_order(7, -50, TimeInForce::FOK, false, 0);
+
+        assert!(killed_qty(&actions).is_none());
+        assert!(book.order_status(first).unwrap().status == OrderStatus::Filled);
+        assert!(book.order_status(second).unwrap().status == OrderStatus::Filled);
+    }
+
+    #[test]
+    fn test_order_status() {
+        let mut book = book();
+        let oid = book.add(10, 50).unwrap();
+        assert!(book.order_status(oid).unwrap().status == OrderStatus::Resting);
+
+        book.match_order(4, -50, TimeInForce::GTC, false, 0);
+        let status = book.order_status(oid).unwrap();
+        assert!(status.status == OrderStatus::PartiallyFilled && status.qty == 6);
+
+        // a user reduce keeps the fill history
+        book.reduce(oid, 1).unwrap();
+        assert!(book.order_status(oid).unwrap().status == OrderStatus::PartiallyFilled);
+
+        book.match_order(5, -50, TimeInForce::GTC, false, 0);
+        assert!(book.order_status(oid).unwrap().status == OrderStatus::Filled);
+        assert!(matches!(book.order_status(oid + 1), Err(BookError::UnknownOrder)));
+    }
+
+    #[test]
+    fn test_live_orders() {
+        let mut book = book();
+        let first = book.add(5, 40).unwrap();
+        let second = book.add(2, 40).unwrap();
+        let third = book.add(10, 60).unwrap();
+        let yes = book.add(3, -20).unwrap();
+        book.delete(second).unwrap();
+        book.match_order(4, -60, TimeInForce::GTC, false, 0);
+
+        // yes side first, then no from the best price down; gone orders aren't listed
+        let live: Vec<(OrderId, OrderStatus, u64)> = book.live_orders().iter().map(|s| (s.oid, s.status, s.qty)).collect();
+        assert!(live == vec![
+            (yes, OrderStatus::Resting, 3),
+            (third, OrderStatus::PartiallyFilled, 6),
+            (first, OrderStatus::Resting, 5),
+        ]);
+
+        book.clear();
+        assert!(book.live_orders().is_empty());
+    }
+
+    #[test]
+    fn test_reduce_to_zero_cancels() {
+        let mut book = book();
+        let oid = book.add(10, 50).unwrap();
+        book.reduce(oid, 10).unwrap();
+        assert!(book.order_status(oid).unwrap().status == OrderStatus::Cancelled);
+        assert!(matches!(book.reduce(oid, 0), Err(BookError::AlreadyCancelled)));
+    }
+
+    #[test]
+    fn test_dead_order_after_level_reuse() {
+        let mut book = book();
+        let first = book.add(10, 50).unwrap();
+        book.delete(first).unwrap();
+
+        // the level empties and refills under a new order; the dead oid must not reach it
+        let second = book.add(7, 50).unwrap();
+        assert!(matches!(book.delete(first), Err(BookError::AlreadyCancelled)));
+        assert!(matches!(book.reduce(first, 1), Err(BookError::AlreadyCancelled)));
+        assert!(book.order_status(second).unwrap().qty == 7);
+    }
+
+    #[test]
+    fn test_wrong_book() {
+        let mut first = Orderbook::with_capacities(0, 10);
+        let mut second = Orderbook::with_capacities(1, 10);
+        let oid = first.add(10, 50).unwrap();
+        assert!(matches!(second.delete(oid), Err(BookError::WrongBook)));
+        assert!(matches!(second.reduce(oid, 1), Err(BookError::WrongBook)));
+        assert!(first.delete(oid).is_ok());
+    }
+
+    #[test]
+    fn test_flush_leaves_other_books() {
+        let mut first = Orderbook::with_capacities(0, 10);
+        let mut second = Orderbook::with_capacities(1, 10);
+        let kept = second.add(5, 50).unwrap();
+        first.add(3, 50).unwrap();
+
+        first.clear();
+        assert!(first.get_level_view().iter().sum::<u64>() == 0);
+        assert!(second.order_status(kept).unwrap().qty == 5);
+        assert!(walk_chain(&second, 50) == vec![kept]);
+    }
+
+    #[test]
+    fn test_book_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Orderbook>();
+    }
+
+    fn small_book(capacity: usize) -> Orderbook {
+        Orderbook::with_capacities(0, capacity)
+    }
+
+    #[test]
+    fn test_slots_reused_oldest_first() {
+        let mut book = small_book(3);
+        let a = book.add(1, 50).unwrap();
+        let b = book.add(1, 50).unwrap();
+        let c = book.add(1, 50).unwrap();
+        book.delete(b).unwrap();
+        book.delete(a).unwrap();
+
+        let b2 = book.add(1, 50).unwrap();
+        let a2 = book.add(1, 50).unwrap();
+        assert!(oid_slot(b2) == oid_slot(b) && oid_slot(a2) == oid_slot(a));
+        assert!(walk_chain(&book, 50) == vec![c, b2, a2]);
+    }
+
+    #[test]
+    fn test_stale_oid_after_reuse() {
+        let mut book = small_book(1);
+        let first = book.add(4, 50).unwrap();
+        book.delete(first).unwrap();
+        let second = book.add(6, 50).unwrap();
+        assert!(oid_slot(first) == oid_slot(second) && first != second);
+
+        // the old oid can't reach the order now in its slot
+        assert!(matches!(book.delete(first), Err(BookError::StaleOrder)));
+        assert!(matches!(book.reduce(first, 1), Err(BookError::StaleOrder)));
+        assert!(matches!(book.amend(first, 1, 50), Err(BookError::StaleOrder)));
+        assert!(matches!(book.order_status(first), Err(BookError::StaleOrder)));
+        assert!(book.order_status(second).unwrap().qty == 6);
+
+        // a flush doesn't hand the same oids out again either
+        book.clear();
+        let third = book.add(1, 50).unwrap();
+        assert!(third != first && third != second);
+        assert!(matches!(book.delete(second), Err(BookError::StaleOrder)));
+    }
+
+    #[test]
+    fn test_slot_retires_on_its_last_generation() {
+        let mut book = small_book(1);
+        let first = book.add(1, 50).unwrap();
+        let mut last = first;
+        for _ in 0..LAST_GENERATION {
+            book.delete(last).unwrap();
+            last = book.add(1, 50).unwrap();
+        }
+        assert!(oid_generation(last) as u32 == LAST_GENERATION);
+        book.delete(last).unwrap();
+
+        // nothing can be handed out under the first oid again, not even after a flush
+        assert!(matches!(book.add(1, 50), Err(BookError::BookFull)));
+        book.clear();
+        assert!(matches!(book.add(1, 50), Err(BookError::BookFull)));
+        assert!(matches!(book.order_status(first), Err(BookError::StaleOrder)));
+        assert!(book.order_status(last).unwrap().status == OrderStatus::Cancelled);
+
+        // and a snapshot keeps it retired
+        let mut bytes = Vec::new();
+        book.snapshot(&mut bytes);
+        let mut book = Orderbook::restore(&mut &bytes[..], 1).unwrap();
+        assert!(matches!(book.add(1, 50), Err(BookError::BookFull)));
+    }
+
+    #[test]
+    fn test_full_book_refuses_resting() {
+        let mut book = small_book(2);
+        book.add(5, -40).unwrap();
+        book.add(5, 70).unwrap();
+
+        let actions = book.match_order(3, 30, TimeInForce::GTC, false, 0);
+        assert!(actions.len() == 1 && matches!(actions[0].typ, OBRespType::ERROR));
+        assert!(unsafe { actions[0].resp.error.err } == BookError::BookFull);
+        assert!(book.get_level_view().iter().sum::<u64>() == 10);
+
+        // nothing needs a slot when the whole order fills or the rest is killed
+        let actions = book.match_order(3, 50, TimeInForce::GTC, false, 0);
+        assert!(matches!(actions[0].typ, OBRespType::EXECUTE));
+        let actions = book.match_order(10, 50, TimeInForce::IOC, false, 0);
+        assert!(matches!(actions.last().unwrap().typ, OBRespType::KILL));
+    }
+
+    #[test]
+    fn test_full_book_rests_in_swept_slot() {
+        let mut book = small_book(2);
+        let taken = book.add(3, 40).unwrap();
+        book.add(5, -70).unwrap();
+        assert!(matches!(book.add(1, 30), Err(BookError::BookFull)));
+
+        // takes the order at 40 whole, the 2 left rest where it was
+        let actions = book.match_order(5, -45, TimeInForce::GTC, false, 0);
+        assert!(matches!(actions[0].typ, OBRespType::EXECUTE));
+        let added = actions.iter().find(|x| matches!(x.typ, OBRespType::ADD)).unwrap();
+        let oid = unsafe { added.resp.add.oid };
+        assert!(oid_slot(oid) == oid_slot(taken) && book.order_status(oid).unwrap().qty == 2);
+    }
+
+    #[test]
+    fn test_replace_amend_in_full_book() {
+        let mut book = small_book(1);
+        let oid = book.add(5, 40).unwrap();
+
+        // the replacement lands in the slot it just gave up but under a new oid
+        let actions = book.amend(oid, 5, 45).unwrap();
+        let summary = amend_summary(&actions);
+        assert!(oid_slot(summary.new_oid) == oid_slot(oid) && summary.new_oid != oid);
+        assert!(matches!(book.order_status(oid), Err(BookError::StaleOrder)));
+        assert!(book.order_status(summary.new_oid).unwrap().qty == 5);
+    }
+
+    // (kind, qty, price, pick); pick chooses the target oid among those handed out so far
+    fn random_ops(seed: u64, n: usize) -> Vec<(u8, u64, i8, usize)> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..n).map(|_| {
+            let price = rng.gen_range(1..99) * if rng.gen_bool(0.5) { -1 } else { 1 };
+            let kind = if rng.gen_ratio(1, 150) { 16 } else { rng.gen_range(0..16) };
+            (kind, rng.gen_range(1..15), price, rng.gen_range(0..1000))
+        }).collect()
+    }
+
+    // runs one op and reports everything it produced, oids it handed out are kept as targets
+    fn step(book: &mut Orderbook, (kind, qty, price, pick): (u8, u64, i8, usize), now: u64, oids: &mut Vec<OrderId>) -> String {
+        let target = if oids.is_empty() { 0 } else { oids[pick % oids.len()] };
+        let tif = [TimeInForce::GTC, TimeInForce::IOC, TimeInForce::FOK, TimeInForce::GTT][pick % 4];
+        let resps = match kind {
+            0..=8 => book.match_order(qty, price, tif, pick % 10 == 0, now + qty % 4),
+            9 => book.market_order(qty, price),
+            10 => book.advance_clock(now),
+            11 => match book.amend(target, qty, price) {
+                Ok(resps) => resps,
+                Err(err) => return format!("{:?}", err),
+            },
+            12 => return format!("{:?}", book.delete(target)),
+            13 => return format!("{:?}", book.reduce(target, qty % 5)),
+            14 | 15 => return format!("{:?}", book.order_status(target)),
+            _ => {
+                book.clear();
+                Vec::new()
+            },
+        };
+        for resp in resps.iter() {
+            match resp.typ {
+                OBRespType::ADD => oids.push(unsafe { resp.resp.add.oid }),
+                OBRespType::AMEND => oids.push(unsafe { resp.resp.amend.new_oid }),
+                _ => (),
+            }
+        }
+        format!("{:?}", resps)
+    }
+
+    #[test]
+    fn test_snapshot_restore_continues() {
+        // a small arena so slots fill up, get freed and come back under new generations
+        for seed in 0..30 {
+            let ops = random_ops(seed, 600);
+            let cut = StdRng::seed_from_u64(seed + 1000).gen_range(0..ops.len());
+
+            let mut straight = small_book(40);
+            let mut straight_oids = Vec::new();
+            let expected: Vec<String> = ops.iter().enumerate()
+                .map(|(i, op)| step(&mut straight, *op, i as u64 / 8, &mut straight_oids))
+                .collect();
+
+            let mut book = small_book(40);
+            let mut oids = Vec::new();
+            for (i, op) in ops[..cut].iter().enumerate() {
+                step(&mut book, *op, i as u64 / 8, &mut oids);
+            }
+            let mut bytes = Vec::new();
+            book.snapshot(&mut bytes);
+            let mut buf = &bytes[..];
+            let mut book = Orderbook::restore(&mut buf, 40).unwrap();
+            assert!(buf.is_empty());
+
+            for (i, op) in ops.iter().enumerate().skip(cut) {
+                assert!(step(&mut book, *op, i as u64 / 8, &mut oids) == expected[i], "seed {} diverged at op {} after a cut at {}", seed, i, cut);
+            }
+            assert!(book.get_level_view() == straight.get_level_view());
+        }
+    }
+
+    #[test]
+    fn test_restore_refuses_bad_snapshots() {
+        let mut book = small_book(10);
+        let oid = book.add(5, -30).unwrap();
+        book.add(2, -30).unwrap();
+        book.delete(oid).unwrap();
+        let mut bytes = Vec::new();
+        book.snapshot(&mut bytes);
+
+        // every cut short of the whole thing runs out of bytes
+        for len in 0..bytes.len() {
+            assert!(Orderbook::restore(&mut &bytes[..len], 10).is_err());
+        }
+        // the arena is bigger than the book restoring it
+        assert!(Orderbook::restore(&mut &bytes[..], 1).is_err());
+        // the freed slot claims to still be resting
+        let status = 2 + 8 + 4 + 4 * 2 + 4;
+        assert!(bytes[status] == OrderStatus::Cancelled as u8);
+        let mut live = bytes.clone();
+        live[status] = OrderStatus::Resting as u8;
+        assert!(Orderbook::restore(&mut &live[..], 10).err().unwrap().kind() == io::ErrorKind::InvalidData);
+    }
 }
@@ -1,3 +1,4 @@
 pub mod pool;
+// book::book::Orderbook is the path everything imports
+#[allow(clippy::module_inception)]
 pub mod book;
-pub mod bump;
@@ -1,76 +1,134 @@
+use std::collections::VecDeque;
 use std::ops;
-use std::mem::MaybeUninit;
-
-const LEVEL_ALLOC : usize = 200;
 
+// Fixed capacity slot store that hands slots back out once they're freed
+//
+// fresh slots are used up first, after that freed slots come back oldest first so an
+// id that just went away is the last one to be reused. every reuse bumps the slot's
+// generation so holders of the old id can tell it's gone; generations survive clear.
+// a slot whose last generation is freed is retired instead, it's never handed out again
 pub struct BasicArena<T> {
-    alloc: Vec<MaybeUninit<T>>,
-    free: Vec<usize>,
-    size: usize
+    alloc: Vec<T>,
+    generations: Vec<u32>,
+    free: VecDeque<usize>,
+    capacity: usize,
+    size: usize,
+    // the last generation a slot can be handed out under
+    last_generation: u32,
 }
 
-// This shouldn't be called with anything that implements drop
-impl<T> MemArena<T> for BasicArena<T> {
-    fn with_capacity(capacity: usize) -> Self {
+impl<T> BasicArena<T> {
+    // slots are handed out at most `last_generation` + 1 times each
+    pub fn with_generations(capacity: usize, last_generation: u32) -> Self {
         BasicArena {
             alloc: Vec::with_capacity(capacity),
-            free: Vec::new(),
-            size: 0
+            generations: Vec::with_capacity(capacity),
+            free: VecDeque::new(),
+            capacity: capacity,
+            size: 0,
+            last_generation: last_generation,
         }
     }
-    fn alloc(self: &mut Self) -> usize {
-        self.size += 1;
-        if self.free.is_empty() {
-            let idx = self.alloc.len();
-            unsafe {
-                self.alloc.set_len(idx + 1);
-            }
-            return idx;
+}
+
+impl<T> MemArena<T> for BasicArena<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        BasicArena::with_generations(capacity, u32::MAX)
+    }
+    fn alloc(self: &mut Self, data: T) -> Option<usize> {
+        let idx = if self.alloc.len() < self.capacity {
+            self.alloc.push(data);
+            self.generations.push(0);
+            self.alloc.len() - 1
         } else {
-            let idx = self.free.pop().unwrap();
-            return idx;
-        }
+            // only slots with a generation left are ever freed
+            let idx = self.free.pop_front()?;
+            self.alloc[idx] = data;
+            self.generations[idx] += 1;
+            idx
+        };
+        self.size += 1;
+        Some(idx)
     }
     fn free(self: &mut Self, idx: usize) {
         self.size -= 1;
-        self.free.push(idx);
+        if self.generations[idx] != self.last_generation {
+            self.free.push_back(idx);
+        }
+    }
+    fn get(self: &Self, idx: usize) -> Option<&T> {
+        self.alloc.get(idx)
     }
-    fn set(self: &mut Self, idx: usize, data: T) {
-        self.alloc[idx].write(data);
+    fn generation(self: &Self, idx: usize) -> u32 {
+        self.generations[idx]
     }
     fn len(self: &Self) -> usize {
         self.size
     }
+    fn is_full(self: &Self) -> bool {
+        self.alloc.len() == self.capacity && self.free.is_empty()
+    }
+    // every slot goes back on the free list in order, keeping its last value, except the
+    // ones on their last generation
     fn clear(self: &mut Self) {
+        let last = self.last_generation;
+        self.free = (0..self.alloc.len()).filter(|idx| self.generations[*idx] != last).collect();
         self.size = 0;
-        self.free.clear();
     }
 }
 
+// what a snapshot needs to put an arena back exactly as it was
+impl<T> BasicArena<T> {
+    // every slot ever handed out, live or not
+    pub fn slots(self: &Self) -> &[T] {
+        &self.alloc
+    }
+    pub fn generations(self: &Self) -> &[u32] {
+        &self.generations
+    }
+    // in the order they'll be handed back out
+    pub fn free_slots(self: &Self) -> impl Iterator<Item = usize> + '_ {
+        self.free.iter().copied()
+    }
+    // the caller makes sure `free` holds exactly the slots that aren't live or retired
+    pub fn restore(capacity: usize, last_generation: u32, slots: Vec<T>, generations: Vec<u32>, free: VecDeque<usize>, live: usize) -> Self {
+        BasicArena {
+            size: live,
+            alloc: slots,
+            generations: generations,
+            free: free,
+            capacity: capacity,
+            last_generation: last_generation,
+        }
+    }
+}
 
 impl<T> ops::Index<usize> for BasicArena<T> {
     type Output = T;
-    fn index<'a>(&'a self, i : usize) -> &'a T {
-        unsafe {
-            self.alloc[i].assume_init_ref()
-        }
+    fn index(&self, i : usize) -> &T {
+        &self.alloc[i]
     }
 }
 
 impl <T> ops::IndexMut<usize> for BasicArena<T> {
     fn index_mut(&mut self, i: usize) -> &mut Self::Output {
-        unsafe {
-            self.alloc[i].assume_init_mut()
-        }
+        &mut self.alloc[i]
     }
 }
 
 pub trait MemArena<T>: ops::IndexMut<usize> + ops::Index<usize> {
     fn with_capacity(capacity: usize) -> Self;
-    fn alloc(self: &mut Self) -> usize;
+    // None once every slot is live or retired
+    fn alloc(self: &mut Self, data: T) -> Option<usize>;
     fn free(self: &mut Self, idx: usize);
-    fn set(self: &mut Self, idx: usize, data: T);
+    // any slot that was ever handed out, freed ones keep their last value until reused
+    fn get(self: &Self, idx: usize) -> Option<&T>;
+    // how many times the slot was handed out before its current holder
+    fn generation(self: &Self, idx: usize) -> u32;
     fn len(self: &Self) -> usize;
+    fn is_empty(self: &Self) -> bool {
+        self.len() == 0
+    }
+    fn is_full(self: &Self) -> bool;
     fn clear(self: &mut Self);
 }
-
@@ -1,20 +1,101 @@
-use crate::comm::stream::InnerStream;
+// the match-based Option/Result plumbing here predates the lint config
+#![allow(clippy::manual_ok_err, clippy::single_match)]
+
+use crate::comm::stream::{InnerStream, Reply};
 use crate::comm::repo::InnerRepo;
 use crate::comm::domain::*;
 use crate::comm::urcp::*;
+use crate::book::book::{BookError, OrderId};
 
 use std::collections::BTreeMap;
-use std::sync::Mutex;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::io;
 
 use tokio::sync::broadcast::Sender;
+use tokio::sync::{oneshot, Mutex, MutexGuard};
+
+// number of books the engine runs
+pub const BOOKS: u16 = 2;
 use serde_json::to_string;
 
+#[derive(Debug)]
+pub enum ClientError {
+    // user can't cover the order
+    InsufficientBalance,
+    // post-only order would have crossed; nothing was debited
+    WouldCross,
+    // order doesn't exist or isn't the user's
+    NotFound,
+    // engine has no free order slots, try again once orders leave the book
+    BookFull,
+    // engine or database call failed
+    Internal,
+    // engine connection is down or orders are still being reconciled after it came back
+    Unavailable,
+}
+
+// engine refusals come back as io errors wrapping the BookError
+fn engine_error(e: io::Error) -> ClientError {
+    if matches!(e.kind(), io::ErrorKind::NotConnected | io::ErrorKind::ConnectionAborted) {
+        return ClientError::Unavailable;
+    }
+    match e.get_ref().and_then(|inner| inner.downcast_ref::<BookError>()) {
+        Some(BookError::BookFull) => ClientError::BookFull,
+        // gone from the book, or never there
+        Some(BookError::AlreadyFilled | BookError::AlreadyCancelled | BookError::StaleOrder | BookError::UnknownOrder | BookError::WrongBook) => ClientError::NotFound,
+        _ => ClientError::Internal,
+    }
+}
+
+// what an order at `price` holds per contract, and gets back for every contract that leaves
+// the book unfilled. holds are taken and released at this rate and nothing else
+fn refund_price(price: i32) -> i32 {
+    if price < 0 {
+        -price
+    } else {
+        100 - price
+    }
+}
+
+// takes `amt` out of the user's balance for an order about to go out
+fn reserve(repo: &mut InnerRepo, uid: i32, amt: i32) -> Result<(), ClientError> {
+    match repo.reserve_balance(uid, amt) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ClientError::InsufficientBalance),
+        Err(_) => Err(ClientError::Internal),
+    }
+}
+
+// a request's place in the order everything went to the engine. each book applies its
+// requests in that order, so settling replies in it keeps user_orders in step with the
+// books: an order's row is in before a later fill against it is recorded
+struct Turn {
+    prev: oneshot::Receiver<()>,
+    // dropped along with the turn, which lets the next one settle
+    _done: oneshot::Sender<()>,
+}
+
+impl Turn {
+    // waits for every earlier request to settle, then locks the repo for this one
+    async fn settle<'a>(self: &mut Self, repo: &'a Mutex<InnerRepo>) -> MutexGuard<'a, InnerRepo> {
+        // the sender only ever goes away, it never sends
+        let _ = (&mut self.prev).await;
+        repo.lock().await
+    }
+}
+
 pub struct InnerClient {
-    stream: Mutex<InnerStream>,
+    // takes requests from many tasks at once, no lock needed
+    stream: InnerStream,
     repo: Mutex<InnerRepo>,
+    // the latest turn handed out; only taken with the repo lock held
+    last_turn: std::sync::Mutex<oneshot::Receiver<()>>,
     sender: Sender<String>,
+    // the link epoch user_orders were last matched against. requests go through only while
+    // the stream is on that connection, so one that dropped and came back before anyone
+    // noticed still counts as down until it's reconciled
+    reconciled: AtomicU64,
 }
 
 pub struct Client {
@@ -31,61 +112,197 @@ impl Clone for Client {
 }
 
 
-// !!! Lock repo first then stream
+// the repo lock keeps balance checks and reservations together and puts requests on the wire
+// in turn order. it's let go for the engine round trip and taken again to settle, after
+// every earlier request has; the stream needs no lock
 impl Client {
-    pub fn new(addr: &'static str, sender: Sender<String>) -> io::Result<Self> {
-        let stream = InnerStream::new(addr)?;
+    pub async fn new(addr: &str, sender: Sender<String>) -> io::Result<Self> {
+        let stream = InnerStream::new(addr).await?;
+        if stream.books() < BOOKS {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!(
+                "engine runs {} books, the api needs {}", stream.books(), BOOKS,
+            )));
+        }
         let repo =  InnerRepo::new()?;
+        let stream_epoch = stream.epoch().unwrap_or(0);
 
+        // the first turn has nothing to wait for
+        let (_, first) = oneshot::channel();
         let inner_client = InnerClient{
-            stream: Mutex::new(stream),
+            stream: stream,
             repo: Mutex::new(repo),
+            last_turn: std::sync::Mutex::new(first),
             sender: sender,
+            reconciled: AtomicU64::new(stream_epoch),
         };
 
-        Ok(Client {
+        let client = Client {
             inner: Arc::new(inner_client),
-        })
+        };
+        tokio::spawn(client.clone().watch_engine());
+        Ok(client)
+    }
+    // degrades the client whenever the engine connection drops and reconciles once it's back
+    async fn watch_engine(self) {
+        let mut link = self.inner.stream.link();
+        while link.changed().await.is_ok() {
+            let epoch = match *link.borrow_and_update() {
+                Some(epoch) => epoch,
+                None => {
+                    println!("ENGINE: connection lost, unavailable until it's back");
+                    continue;
+                },
+            };
+            if epoch == self.inner.reconciled.load(Ordering::SeqCst) {
+                continue;
+            }
+            // a failure here is the connection dropping again, the next reconnect retries
+            match self.reconcile_orders(epoch).await {
+                Ok(()) => println!("ENGINE: reconnected, orders reconciled"),
+                Err(e) => println!("ENGINE: reconcile failed: {}", e),
+            }
+        }
+    }
+    // the engine may have lost or changed orders while we couldn't see it, and requests in
+    // flight at the drop may have left orders on it we never heard back about. each book
+    // lists what it has resting: orders it still has keep what's left, the rest of ours are
+    // refunded and dropped, and its orders nobody here owns are cancelled.
+    //
+    // qty an order lost while it stayed on the book most likely filled, and those fills
+    // were never recorded. it isn't refunded: the gap goes to order_gaps for someone to
+    // settle by hand
+    async fn reconcile_orders(&self, epoch: u64) -> io::Result<()> {
+        // requests that went out before the drop settle first, whatever they got back
+        let mut turn = {
+            let _repo = self.inner.repo.lock().await;
+            self.take_turn()
+        };
+        let mut repo = turn.settle(&self.inner.repo).await;
+        let stream = &self.inner.stream;
+
+        // every book's listing is on the wire before the first reply is read
+        let replies = (0..BOOKS)
+            .map(|book_id| stream.send(OBReqType::ORDERS, OBRequest { orders: OrdersRequest::new(book_id) }))
+            .collect::<io::Result<Vec<Reply>>>()?;
+
+        // oid -> (book, qty resting)
+        let mut live: BTreeMap<OrderId, (u16, u64)> = BTreeMap::new();
+        for (book_id, reply) in replies.into_iter().enumerate() {
+            for resp in reply.wait().await? {
+                match resp.typ {
+                    OBRespType::STATUS => {
+                        let status = unsafe { resp.resp.status };
+                        live.insert(status.oid, (book_id as u16, status.qty));
+                    },
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "expected status")),
+                }
+            }
+        }
+
+        let orders = repo.get_all_orders().map_err(io::Error::other)?;
+        for order in orders.iter() {
+            let held = order.qty as u64;
+            match live.remove(&(order.id as OrderId)) {
+                Some((_, qty)) if qty >= held => (),
+                Some((_, qty)) => {
+                    println!("ENGINE: order {} came back with {} of {}, flagged", order.id, qty, held);
+                    repo.flag_order_gap(order, held - qty).map_err(io::Error::other)?;
+                    repo.set_order_qty(order.id as OrderId, qty).map_err(io::Error::other)?;
+                },
+                None => {
+                    let _ = repo.modify_user_balance(order.user_fk, refund_price(order.price) * order.qty);
+                    let _ = repo.delete_order(order.id as OrderId);
+                },
+            }
+        }
+
+        // what's left never got a row; whoever sent it was refunded when the reply didn't come
+        let cancels = live.iter()
+            .map(|(oid, (book_id, _))| stream.send(OBReqType::CANCEL, OBRequest { cancel: CancelRequest::new(*oid, *book_id) }))
+            .collect::<io::Result<Vec<Reply>>>()?;
+        for reply in cancels {
+            reply.wait().await?;
+        }
+        if !live.is_empty() {
+            println!("ENGINE: cancelled {} orders nobody owns", live.len());
+        }
+
+        self.inner.reconciled.store(epoch, Ordering::SeqCst);
+        Ok(())
+    }
+    // the turn after every request sent so far; taken with the repo lock held, in the same
+    // breath as the request goes out
+    fn take_turn(&self) -> Turn {
+        let (done, next) = oneshot::channel();
+        let prev = std::mem::replace(&mut *self.inner.last_turn.lock().unwrap(), next);
+        Turn { prev: prev, _done: done }
+    }
+    // with the repo lock held, puts a request on the wire and takes its turn. one that
+    // can't go out hands back what was reserved for it
+    fn send(&self, repo: &mut InnerRepo, uid: i32, reserved: i32, typ: OBReqType, req: OBRequest) -> Result<(Reply, Turn), ClientError> {
+        match self.inner.stream.send(typ, req) {
+            Ok(reply) => Ok((reply, self.take_turn())),
+            Err(e) => {
+                let _ = repo.modify_user_balance(uid, reserved);
+                Err(engine_error(e))
+            },
+        }
+    }
+    // checked with the repo lock held, so nothing slips in while orders are reconciled
+    fn available(&self) -> Result<(), ClientError> {
+        if self.inner.stream.epoch() == Some(self.inner.reconciled.load(Ordering::SeqCst)) {
+            Ok(())
+        } else {
+            Err(ClientError::Unavailable)
+        }
     }
-    pub fn get_user(&self, sub: String) -> Option<User> {
-        let mut repo = self.inner.repo.lock().unwrap();
+    pub async fn get_user(&self, sub: String) -> Option<User> {
+        let mut repo = self.inner.repo.lock().await;
         match repo.get_user(sub) {
             Ok(usr) => Some(usr),
             _ => None
         }
     }
-    pub fn create_user(&self, sub: String) -> Option<()> {
-        let mut repo = self.inner.repo.lock().unwrap();
+    pub async fn create_user(&self, sub: String) -> Option<()> {
+        let mut repo = self.inner.repo.lock().await;
         match repo.create_user(sub) {
             Ok(_) => Some(()),
             _ => None
         }
     }
-    pub fn add_order(&self, user: &User, price: i8, qty: u64, book_id: u16) -> Option<AddResponse> {
-        let mut repo = self.inner.repo.lock().unwrap();
-        let mut stream = self.inner.stream.lock().unwrap();
-    
-        let math_price: u64 = price.abs() as u64;
+    pub async fn add_order(&self, user: &User, req: AddRequest) -> Result<AddResponse, ClientError> {
+        let (price, qty, book_id) = (req.price, req.qty, req.ob_id);
+        let math_price: u64 = refund_price(price as i32) as u64;
         let req_balance: i32 = match (qty * math_price).try_into() {
             Ok(bal) => bal,
-            Err(_) => return None
+            Err(_) => return Err(ClientError::InsufficientBalance)
         };
 
-        if user.balance < req_balance  {
-            return None;
-        }
+        let (reply, mut turn) = {
+            let mut repo = self.inner.repo.lock().await;
+            self.available()?;
+            reserve(&mut repo, user.id, req_balance)?;
 
-        println!("Adding order P: {} Q: {} B: {}", price, qty, book_id);
+            println!("Adding order P: {} Q: {} B: {}", price, qty, book_id);
+            self.send(&mut repo, user.id, req_balance, OBReqType::ADD, OBRequest { add: req })?
+        };
 
-        let ret = match stream.add_order(qty, price, book_id) {
+        let ret = reply.wait().await;
+        let mut repo = turn.settle(&self.inner.repo).await;
+        let ret = match ret {
             Err(e) => {
                 println!("{}", e);
-                return None;
+                let _ = repo.modify_user_balance(user.id, req_balance);
+                return Err(engine_error(e));
             },
             Ok(data) => data,
         };
 
-        let _ = repo.modify_user_balance(user.id, -req_balance);
+        // a rejected order is the only response, none of the reservation was used
+        if ret.iter().any(|x| matches!(x.typ, OBRespType::REJECT)) {
+            let _ = repo.modify_user_balance(user.id, req_balance);
+            return Err(ClientError::WouldCross);
+        }
 
         let mut add_response = None;
 
@@ -94,25 +311,15 @@ impl Client {
             unsafe {
                 match result {
                     OBResponseWrapper { resp: OBResponse { execute: resp }, typ: OBRespType::EXECUTE } => {
-                        repo.create_contract(user.id, resp.executed_oid, resp.qty, book_id).unwrap();
-                        let execute_packet = ApiExecuteResponse {
-                            typ: String::from("execute"),
-                            data: ApiExecuteInner {
-                                oid: resp.executed_oid,
-                                qty: resp.qty,
-                            }
-                        };
-                        if let Ok(json) = to_string(&execute_packet) {
-                            match self.inner.sender.send(json) {
-                                Err(e) => println!("ERROR BCAST: {}", e),
-                                _ => (),
-                            }
-                        }
-                        
+                        self.record_execution(&mut repo, user, resp, book_id);
                     },
                     OBResponseWrapper { resp: OBResponse { add: resp }, typ: OBRespType::ADD } => {
                         repo.add_order_to_user(resp.oid, book_id, price, resp.qty, user.id).unwrap();
-                        add_response = Some(resp.clone());
+                        add_response = Some(*resp);
+                    },
+                    OBResponseWrapper { resp: OBResponse { kill: resp }, typ: OBRespType::KILL } => {
+                        // the killed part never made it to the book, hand its reservation back
+                        let _ = repo.modify_user_balance(user.id, (resp.qty * math_price) as i32);
                     },
                     _ => unreachable!()
                 }
@@ -120,62 +327,296 @@ impl Client {
         }
 
         match add_response {
-            Some(e) => Some(e),
-            None => Some(AddResponse{
+            Some(e) => Ok(e),
+            None => Ok(AddResponse{
                 qty: 0,
                 oid: 0,
             })
         }
     }
-    pub fn reduce_order(&self, user: &User, oid: usize, qty: u64, book_id: u16) -> Option<()> {
-        let mut repo = self.inner.repo.lock().unwrap();
-        let mut stream = self.inner.stream.lock().unwrap();
+    // market orders reserve against the worst case and refund whatever the fills didn't use
+    pub async fn market_order(&self, user: &User, limit: i8, qty: u64, book_id: u16) -> Result<MarketResponse, ClientError> {
+        let worst_price: u64 = refund_price(limit as i32) as u64;
+        let req_balance: i32 = match (qty * worst_price).try_into() {
+            Ok(bal) => bal,
+            Err(_) => return Err(ClientError::InsufficientBalance)
+        };
 
-        // check that we can actually perform this operation
+        let (reply, mut turn) = {
+            let mut repo = self.inner.repo.lock().await;
+            self.available()?;
+            reserve(&mut repo, user.id, req_balance)?;
 
-        match stream.reduce_order(oid, qty, book_id) {
-            Ok(_) => {
-                repo.modify_user_balance(user.id, -(qty as i32)).unwrap();
-                Some(())
+            println!("Market order L: {} Q: {} B: {}", limit, qty, book_id);
+            let req = OBRequest { market: MarketRequest::new(qty, limit, book_id) };
+            self.send(&mut repo, user.id, req_balance, OBReqType::MARKET, req)?
+        };
+
+        let ret = reply.wait().await;
+        let mut repo = turn.settle(&self.inner.repo).await;
+        let ret = match ret {
+            Err(e) => {
+                println!("{}", e);
+                let _ = repo.modify_user_balance(user.id, req_balance);
+                return Err(engine_error(e));
             },
-            _ => None
+            Ok(data) => data,
+        };
+
+        let mut summary = None;
+
+        for result in ret.iter() {
+            println!("In Client: {:?}", result);
+            unsafe {
+                match result {
+                    OBResponseWrapper { resp: OBResponse { execute: resp }, typ: OBRespType::EXECUTE } => {
+                        self.record_execution(&mut repo, user, resp, book_id);
+                    },
+                    OBResponseWrapper { resp: OBResponse { market: resp }, typ: OBRespType::MARKET } => {
+                        summary = Some(*resp);
+                    },
+                    // the unfilled part is covered by the refund below
+                    OBResponseWrapper { typ: OBRespType::KILL, .. } => (),
+                    _ => unreachable!()
+                }
+            }
+        }
+
+        let summary = match summary {
+            Some(summary) => summary,
+            None => return Err(ClientError::Internal),
+        };
+
+        // what the fills used of the hold: a YES taker pays the NO prices it took, a NO taker's
+        // fills keep what they held the way an ADD's do. the unfilled rest comes back
+        let spent = if limit < 0 { summary.notional } else { summary.filled * worst_price };
+        let _ = repo.modify_user_balance(user.id, req_balance - spent as i32);
+
+        Ok(summary)
+    }
+    // cancel-replace in one engine round trip; see Orderbook::amend for when priority is kept
+    pub async fn amend_order(&self, user: &User, oid: OrderId, book_id: u16, qty: u64, price: i8) -> Result<AmendResponse, ClientError> {
+        let math_price: u64 = refund_price(price as i32) as u64;
+        let new_cost: i32 = match (qty * math_price).try_into() {
+            Ok(bal) => bal,
+            Err(_) => return Err(ClientError::InsufficientBalance)
+        };
+
+        let (reply, mut turn, order) = {
+            let mut repo = self.inner.repo.lock().await;
+            self.available()?;
+
+            let order = match repo.get_order(oid) {
+                Ok(order) if order.user_fk == user.id => order,
+                _ => return Err(ClientError::NotFound),
+            };
+
+            // the whole new size is held until the engine says what it did. the old order may
+            // have lost qty to fills that haven't settled yet, so its refund can't pay for any
+            // of it up front; an amend that keeps its place gets all of this back
+            reserve(&mut repo, user.id, new_cost)?;
+
+            let req = OBRequest { amend: AmendRequest::new(oid, qty, price, book_id) };
+            let (reply, turn) = self.send(&mut repo, user.id, new_cost, OBReqType::AMEND, req)?;
+            (reply, turn, order)
+        };
+
+        let ret = reply.wait().await;
+        let mut repo = turn.settle(&self.inner.repo).await;
+        let ret = match ret {
+            Err(e) => {
+                println!("AMEND: {}", e);
+                let _ = repo.modify_user_balance(user.id, new_cost);
+                return Err(engine_error(e));
+            },
+            Ok(data) => data,
+        };
+
+        // a post only order that would cross at the new price, left as it was
+        if ret.iter().any(|x| matches!(x.typ, OBRespType::REJECT)) {
+            let _ = repo.modify_user_balance(user.id, new_cost);
+            return Err(ClientError::WouldCross);
+        }
+
+        let mut amend_response = None;
+
+        for result in ret.iter() {
+            println!("In Client: {:?}", result);
+            unsafe {
+                match result {
+                    // always first, so the old row is settled before a new one can be inserted.
+                    // what the old order held comes back for the qty the engine pulled off it
+                    OBResponseWrapper { resp: OBResponse { amend: resp }, typ: OBRespType::AMEND } => {
+                        let released = refund_price(order.price) * resp.pulled as i32;
+                        if resp.new_oid == oid {
+                            let _ = repo.modify_user_balance(user.id, new_cost + released);
+                            let _ = repo.set_order_qty(oid, resp.qty);
+                        } else {
+                            let _ = repo.modify_user_balance(user.id, released);
+                            let _ = repo.delete_order(oid);
+                        }
+                        amend_response = Some(*resp);
+                    },
+                    OBResponseWrapper { resp: OBResponse { execute: resp }, typ: OBRespType::EXECUTE } => {
+                        self.record_execution(&mut repo, user, resp, book_id);
+                    },
+                    OBResponseWrapper { resp: OBResponse { add: resp }, typ: OBRespType::ADD } => {
+                        repo.add_order_to_user(resp.oid, book_id, price, resp.qty, user.id).unwrap();
+                    },
+                    OBResponseWrapper { resp: OBResponse { kill: resp }, typ: OBRespType::KILL } => {
+                        let _ = repo.modify_user_balance(user.id, (resp.qty * math_price) as i32);
+                    },
+                    _ => unreachable!()
+                }
+            }
+        }
+
+        match amend_response {
+            Some(resp) => Ok(resp),
+            None => Err(ClientError::Internal),
         }
     }
-    pub fn cancel_order(&self, user: &User, oid: usize, book_id: u16) -> Option<()> {
-        let mut repo = self.inner.repo.lock().unwrap();
-        let mut stream = self.inner.stream.lock().unwrap();
-
-        let order_adj_qty = match repo.get_order(oid) {
-            Ok(order) => {
-                let rp = if order.price < 0 {
-                    -order.price
-                } else {
-                    100 - order.price
-                };
-                rp * order.qty
+    fn record_execution(&self, repo: &mut InnerRepo, user: &User, resp: &ExecuteResponse, book_id: u16) {
+        repo.create_contract(user.id, resp.executed_oid, resp.qty, book_id).unwrap();
+        let execute_packet = ApiExecuteResponse {
+            typ: String::from("execute"),
+            data: ApiExecuteInner {
+                oid: resp.executed_oid,
+                qty: resp.qty,
             }
-            _ => return None
         };
+        if let Ok(json) = to_string(&execute_packet) {
+            match self.inner.sender.send(json) {
+                Err(e) => println!("ERROR BCAST: {}", e),
+                _ => (),
+            }
+        }
+    }
+    pub async fn reduce_order(&self, user: &User, oid: OrderId, qty: u64, book_id: u16) -> Result<(), ClientError> {
+        let (reply, mut turn) = {
+            let mut repo = self.inner.repo.lock().await;
+            self.available()?;
+
+            match repo.get_order(oid) {
+                Ok(order) if order.user_fk == user.id => (),
+                _ => return Err(ClientError::NotFound),
+            }
 
-        match repo.modify_user_balance(user.id, order_adj_qty) {
-            Err(_) => return None,
-            _ => ()
+            let req = OBRequest { reduce: ReduceRequest::new(oid, qty, book_id) };
+            self.send(&mut repo, user.id, 0, OBReqType::REDUCE, req)?
         };
 
-        match stream.cancel_order(oid, book_id) {
+        let ret = reply.wait().await;
+        let mut repo = turn.settle(&self.inner.repo).await;
+        if let Err(e) = ret {
+            println!("REDUCE: {}", e);
+            return Err(engine_error(e));
+        }
+
+        // the book took `qty` off what fills settled ahead of this one left
+        let order = repo.get_order(oid).map_err(|_| ClientError::Internal)?;
+        let left = (order.qty as u64).saturating_sub(qty);
+        repo.modify_user_balance(order.user_fk, refund_price(order.price) * (order.qty - left as i32)).map_err(|_| ClientError::Internal)?;
+        let stored = if left == 0 { repo.delete_order(oid) } else { repo.set_order_qty(oid, left) };
+        stored.map_err(|_| ClientError::Internal)?;
+        Ok(())
+    }
+    pub async fn cancel_order(&self, user: &User, oid: OrderId, book_id: u16) -> Result<(), ClientError> {
+        let (reply, mut turn) = {
+            let mut repo = self.inner.repo.lock().await;
+            self.available()?;
+
+            match repo.get_order(oid) {
+                Ok(order) if order.user_fk == user.id => (),
+                _ => return Err(ClientError::NotFound),
+            }
+
+            let req = OBRequest { cancel: CancelRequest::new(oid, book_id) };
+            self.send(&mut repo, user.id, 0, OBReqType::CANCEL, req)?
+        };
+
+        let ret = reply.wait().await;
+        let mut repo = turn.settle(&self.inner.repo).await;
+        // only refund once the book has actually let go of the order, and only what fills
+        // settled ahead of this one left of it
+        match ret {
             Ok(_) => {
-                let _ = repo.delete_order(oid);
-                Some(())
+                if let Ok(order) = repo.get_order(oid) {
+                    let _ = repo.modify_user_balance(order.user_fk, refund_price(order.price) * order.qty);
+                    let _ = repo.delete_order(oid);
+                }
+                Ok(())
             },
             Err(e) => {
                 println!("CANCEL: {}", e);
-                None
+                Err(engine_error(e))
             }
         }
     }
-    pub fn flush_exchange(&self, top: bool, right: bool) -> Option<()> {
-        let mut repo = self.inner.repo.lock().unwrap();
-        let mut stream = self.inner.stream.lock().unwrap();
+    // advances every book clock to `now`, refunding and forgetting orders that expired
+    pub async fn expire_orders(&self, now: u64) -> Option<()> {
+        let (replies, mut turn) = {
+            let _repo = self.inner.repo.lock().await;
+            let stream = &self.inner.stream;
+            // clocks catch up on the first tick after the engine is back
+            self.available().ok()?;
+
+            // every book's tick is on the wire before the first reply is read
+            let replies = (0..BOOKS)
+                .map(|book_id| stream.send(OBReqType::TICK, OBRequest { tick: TickRequest::new(now, book_id) }))
+                .collect::<io::Result<Vec<Reply>>>();
+            match replies {
+                Ok(replies) => (replies, self.take_turn()),
+                Err(e) => {
+                    println!("TICK: {}", e);
+                    return None;
+                }
+            }
+        };
+
+        let mut expired = Vec::new();
+        for reply in replies {
+            match reply.wait().await {
+                Ok(data) => expired.extend(data),
+                Err(e) => {
+                    println!("TICK: {}", e);
+                    return None;
+                }
+            }
+        }
+
+        let mut repo = turn.settle(&self.inner.repo).await;
+        for result in expired.iter() {
+            unsafe {
+                match result {
+                    OBResponseWrapper { resp: OBResponse { expired: resp }, typ: OBRespType::EXPIRED } => {
+                        if let Ok(order) = repo.get_order(resp.oid) {
+                            let _ = repo.modify_user_balance(order.user_fk, refund_price(order.price) * resp.qty as i32);
+                            let _ = repo.delete_order(resp.oid);
+                        }
+                    },
+                    _ => unreachable!()
+                }
+            }
+        }
+
+        Some(())
+    }
+    pub async fn flush_exchange(&self, top: bool, right: bool) -> Option<()> {
+        let (replies, mut turn) = {
+            let _repo = self.inner.repo.lock().await;
+            let stream = &self.inner.stream;
+            // paying out while the books can't be flushed would leave orders to be paid twice
+            self.available().ok()?;
+
+            let replies: Vec<io::Result<Reply>> = (0..BOOKS)
+                .map(|book_id| stream.send(OBReqType::FLUSH, OBRequest { flush: FlushRequest::new(book_id) }))
+                .collect();
+            (replies, self.take_turn())
+        };
+
+        // orders sent before the flush are settled first, so they're in what gets paid out
+        let mut repo = turn.settle(&self.inner.repo).await;
 
         let mut map: BTreeMap<i32, i32> = BTreeMap::new();
 
@@ -183,12 +624,7 @@ impl Client {
 
         if let Ok(orders) = orders {
             for order in orders.iter() {
-                let rp = if order.price < 0 {
-                    -order.price
-                } else {
-                    100 - order.price
-                };
-                let return_balance = rp * order.qty;
+                let return_balance = refund_price(order.price) * order.qty;
                 match map.get_mut(&order.user_fk) {
                     Some(v) => {
                         *v += return_balance;
@@ -236,35 +672,39 @@ impl Client {
         }
 
         let _ = repo.drop_orders();
+        drop(repo);
 
-        // todo(nw) after done call stream.flush
-        let _ = stream.flush_book(0);
-        let _ = stream.flush_book(1);
+        for reply in replies.into_iter().flatten() {
+            let _ = reply.wait().await;
+        }
 
         None 
     }
-    pub fn get_contracts_for_user(&self, uid: i32) -> Option<Vec<Contract>> {
-        let mut repo = self.inner.repo.lock().unwrap();
+    pub async fn get_contracts_for_user(&self, uid: i32) -> Option<Vec<Contract>> {
+        let mut repo = self.inner.repo.lock().await;
         match repo.get_contracts_for_user(uid) {
             Err(_) => None,
             Ok(data) => Some(data),
         }
     }
+    // rebuild the cached depth from the engine, e.g. if deltas were missed
+    pub async fn resync_levels(&self) -> io::Result<()> {
+        self.inner.stream.resync_all().await
+    }
     pub fn get_ob_levels(&self) -> Vec<std::collections::BTreeMap<i8, u64>> {
-        let stream = self.inner.stream.lock().unwrap();
-        stream.get_price_levels()
+        self.inner.stream.get_price_levels()
     }
 
-    pub fn get_orders(&self, user: &User) -> Option<Vec<UserOrder>> {
-        let mut repo = self.inner.repo.lock().unwrap();
+    pub async fn get_orders(&self, user: &User) -> Option<Vec<UserOrder>> {
+        let mut repo = self.inner.repo.lock().await;
 
         match repo.get_orders(user.id) {
             Ok(orders) => Some(orders),
             _ => None
         }
     }
-    pub fn get_leaderboard(&self) -> Option<Vec<User>> {
-        let mut repo = self.inner.repo.lock().unwrap();
+    pub async fn get_leaderboard(&self) -> Option<Vec<User>> {
+        let mut repo = self.inner.repo.lock().await;
         match repo.get_order_leaderboard() {
             Ok(data) => Some(data),
             _ => None
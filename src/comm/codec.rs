@@ -0,0 +1,118 @@
+use crate::comm::urcp::*;
+
+use bytes::{Buf, BytesMut};
+use std::io::{Error, Result};
+use tokio_util::codec::{Decoder, Encoder};
+
+// tokio framing for the client end of URCP: (corr, type, request) out, (corr, response) in
+pub struct ClientCodec;
+
+impl Decoder for ClientCodec {
+    type Item = (u32, OBResponseWrapper);
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if src.len() < HEADER {
+            src.reserve(HEADER - src.len());
+            return Ok(None);
+        }
+        let len = Frame::wire_len(src);
+        if src.len() < len {
+            src.reserve(len - src.len());
+            return Ok(None);
+        }
+
+        let frame = Frame::from_wire(&src[..len]);
+        src.advance(len);
+        Ok(Some((frame.corr, decode_response(&frame)?)))
+    }
+}
+
+impl Encoder<(u32, OBReqType, OBRequest)> for ClientCodec {
+    type Error = Error;
+
+    fn encode(&mut self, (corr, typ, req): (u32, OBReqType, OBRequest), dst: &mut BytesMut) -> Result<()> {
+        dst.extend_from_slice(&encode_request(corr, &typ, &req));
+        Ok(())
+    }
+}
+
+// the same for the handshake, which has its own framing: a HELLO out, the engine's answer in
+pub struct HandshakeCodec;
+
+impl Decoder for HandshakeCodec {
+    type Item = OBResponseWrapper;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if src.len() < HELLO_HEADER {
+            src.reserve(HELLO_HEADER - src.len());
+            return Ok(None);
+        }
+        let len = Frame::hello_wire_len(src);
+        if src.len() < len {
+            src.reserve(len - src.len());
+            return Ok(None);
+        }
+
+        let frame = Frame::from_hello_wire(&src[..len]);
+        src.advance(len);
+        Ok(Some(decode_response(&frame)?))
+    }
+}
+
+impl Encoder<HelloRequest> for HandshakeCodec {
+    type Error = Error;
+
+    fn encode(&mut self, hello: HelloRequest, dst: &mut BytesMut) -> Result<()> {
+        dst.extend_from_slice(&encode_hello(&hello));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_in_pieces() {
+        let mut wire = encode_response(7, &OBRespType::KILL, &OBResponse { kill: KillResponse::new(6) });
+        wire.extend(encode_response(8, &OBRespType::DELIM, &OBResponse { end: DelimResponse {} }));
+
+        // a byte at a time, nothing comes out until a frame is whole
+        let mut codec = ClientCodec;
+        let mut buf = BytesMut::new();
+        let mut out = Vec::new();
+        for byte in wire {
+            buf.extend_from_slice(&[byte]);
+            if let Some(item) = codec.decode(&mut buf).unwrap() {
+                out.push(item);
+            }
+        }
+        assert!(buf.is_empty());
+        assert!(out.len() == 2);
+        assert!(out[0].0 == 7 && unsafe { out[0].1.resp.kill.qty } == 6);
+        assert!(out[1].0 == 8 && matches!(out[1].1.typ, OBRespType::DELIM));
+    }
+
+    #[test]
+    fn test_handshake_leaves_the_rest() {
+        let hello = HelloResponse::new(PROTOCOL_VERSION, FEATURES, 2);
+        let mut buf = BytesMut::from(&encode_hello_response(&OBRespType::HELLO, &OBResponse { hello: hello })[..]);
+        let view = encode_response(1, &OBRespType::DELIM, &OBResponse { end: DelimResponse {} });
+        buf.extend_from_slice(&view);
+
+        let resp = HandshakeCodec.decode(&mut buf).unwrap().unwrap();
+        assert!(matches!(resp.typ, OBRespType::HELLO) && unsafe { resp.resp.hello.books } == 2);
+        assert!(buf[..] == view[..]);
+        assert!(ClientCodec.decode(&mut buf).unwrap().unwrap().0 == 1);
+    }
+
+    #[test]
+    fn test_encode_matches_write_request() {
+        let req = OBRequest { cancel: CancelRequest::new(3, 1) };
+        let mut buf = BytesMut::new();
+        ClientCodec.encode((9, OBReqType::CANCEL, req), &mut buf).unwrap();
+        assert!(buf[..] == encode_request(9, &OBReqType::CANCEL, &req)[..]);
+    }
+}
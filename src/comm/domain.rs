@@ -1,3 +1,4 @@
+use crate::book::book::OrderId;
 use serde::Serialize;
 
 #[derive(Serialize)]
@@ -17,7 +18,7 @@ pub struct Contract {
 
 #[derive(Serialize,Debug)]
 pub struct UserOrder {
-    pub id: i32,
+    pub id: i64,
     pub book_id: i32,
     pub price: i32,
     pub qty: i32,
@@ -49,7 +50,7 @@ pub struct ApiExecuteResponse {
 
 #[derive(Serialize)]
 pub struct ApiExecuteInner {
-    pub oid: usize,
+    pub oid: OrderId,
     pub qty: u64
 }
 
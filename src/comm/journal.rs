@@ -0,0 +1,390 @@
+use crate::comm::manager::Manager;
+use crate::comm::urcp::*;
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// Append-only log of every request that changed a book
+//
+// a record is the request's sequence number (u64 LE) followed by the request as a URCP frame
+// with corr 0. books only depend on the order of their own requests, so replaying the records
+// in sequence into a fresh Manager rebuilds the same books with the same oids. a crash can
+// leave the last record half written; it was never acknowledged and replay drops it.
+// a TICK that expired nothing only gets a record in front of its book's next one
+pub struct Journal {
+    file: File,
+    next_seq: u64,
+    // records written since the last sync
+    unsynced: bool,
+}
+
+pub struct Record {
+    pub seq: u64,
+    pub req: OBRequestWrapper,
+}
+
+impl Journal {
+    // replays `path` into `manager` and opens it for appending after the last whole record.
+    // `after` is the last record the manager already has, e.g. from a snapshot; older records
+    // are left over from before the journal was truncated. a missing journal is an empty one
+    pub fn replay<P: AsRef<Path>>(path: P, manager: &mut Manager, after: u64) -> Result<Self> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut records = Records::new(BufReader::new(&mut file), manager.book_size() as u16);
+        let mut next_seq = after + 1;
+        for record in records.by_ref() {
+            let record = record?;
+            if record.seq < next_seq {
+                continue;
+            }
+            if record.seq > next_seq {
+                return Err(Error::new(ErrorKind::InvalidData, format!(
+                    "journal resumes at record {}, the books stop at {}", record.seq, next_seq - 1,
+                )));
+            }
+            manager.handle(&record.req);
+            next_seq = record.seq + 1;
+        }
+        let intact = records.intact();
+
+        // appends go after what replay kept, not after a torn record
+        file.set_len(intact)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Journal {
+            file: file,
+            next_seq: next_seq,
+            unsynced: false,
+        })
+    }
+    // the record is only on disk once sync() returns; its request may not be answered before
+    pub fn append(self: &mut Self, req: &OBRequestWrapper) -> Result<u64> {
+        let seq = self.next_seq;
+        let mut record = seq.to_le_bytes().to_vec();
+        record.extend_from_slice(&encode_request(0, &req.typ, &req.req));
+        self.file.write_all(&record)?;
+        self.next_seq += 1;
+        self.unsynced = true;
+        Ok(seq)
+    }
+    // one sync for every record appended since the last
+    pub fn sync(self: &mut Self) -> Result<()> {
+        if self.unsynced {
+            self.file.sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+    pub fn next_seq(self: &Self) -> u64 {
+        self.next_seq
+    }
+    // once a snapshot holds every record, the journal starts over empty; the next record
+    // still follows on from the last one
+    pub fn truncate(self: &mut Self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()
+    }
+}
+
+// every whole record in a journal, in order. stops quietly at a torn last record and fails
+// on anything that isn't one
+pub struct Records<R> {
+    reader: R,
+    books: u16,
+    last_seq: Option<u64>,
+    intact: u64,
+    done: bool,
+}
+
+impl<R: Read> Records<R> {
+    pub fn new(reader: R, books: u16) -> Self {
+        Records {
+            reader: reader,
+            books: books,
+            last_seq: None,
+            intact: 0,
+            done: false,
+        }
+    }
+    // bytes of whole records read so far
+    pub fn intact(self: &Self) -> u64 {
+        self.intact
+    }
+    fn read_record(self: &mut Self) -> Result<Option<Record>> {
+        let mut seq = [0u8; 8];
+        let mut read = 0;
+        while read < seq.len() {
+            match self.reader.read(&mut seq[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        // the end, or a record that was cut off before its frame
+        if read < seq.len() {
+            return Ok(None);
+        }
+        let seq = u64::from_le_bytes(seq);
+
+        let frame = match read_frame(&mut self.reader) {
+            Ok(Ok(frame)) => frame,
+            Ok(Err(ProtocolError::Truncated)) => return Ok(None),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Ok(Err(err)) => return Err(Error::new(ErrorKind::InvalidData, format!("journal record {}: {}", seq, err))),
+            Err(e) => return Err(e),
+        };
+        let req = decode_request(&frame, self.books)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, format!("journal record {}: {}", seq, err)))?;
+        match self.last_seq {
+            Some(last) if seq != last + 1 => return Err(Error::new(ErrorKind::InvalidData, format!(
+                "journal skips from record {} to {}", last, seq,
+            ))),
+            _ => (),
+        }
+
+        self.last_seq = Some(seq);
+        self.intact += (8 + HEADER + frame.payload.len()) as u64;
+        Ok(Some(Record { seq: seq, req: req }))
+    }
+}
+
+impl<R: Read> Iterator for Records<R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = self.read_record().transpose();
+        if !matches!(record, Some(Ok(_))) {
+            self.done = true;
+        }
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::book::{OrderId, TimeInForce};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const BOOKS: u16 = 3;
+
+    fn journal_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("fish-{}-{}.journal", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn wire(resps: &[OBResponseWrapper]) -> Vec<u8> {
+        resps.iter().flat_map(|resp| encode_response(0, &resp.typ, &resp.resp)).collect()
+    }
+
+    // a mix of every request that changes a book, aimed at orders that were added earlier
+    fn request(rng: &mut StdRng, oids: &[(OrderId, u16)], now: u64) -> OBRequestWrapper {
+        let price = rng.gen_range(1..99) * if rng.gen_bool(0.5) { -1 } else { 1 };
+        let ob_id = rng.gen_range(0..BOOKS);
+        let target = if oids.is_empty() { (0, ob_id) } else { oids[rng.gen_range(0..oids.len())] };
+        let (typ, req) = match rng.gen_range(0..20) {
+            0..=9 => {
+                let tif = [TimeInForce::GTC, TimeInForce::IOC, TimeInForce::FOK, TimeInForce::GTT][rng.gen_range(0..4)];
+                (OBReqType::ADD, OBRequest { add: AddRequest::new(rng.gen_range(1..20), price, ob_id, tif, rng.gen_bool(0.1), now + rng.gen_range(1..5)) })
+            },
+            10..=11 => (OBReqType::MARKET, OBRequest { market: MarketRequest::new(rng.gen_range(1..20), price, ob_id) }),
+            12..=13 => (OBReqType::CANCEL, OBRequest { cancel: CancelRequest::new(target.0, target.1) }),
+            14..=15 => (OBReqType::REDUCE, OBRequest { reduce: ReduceRequest::new(target.0, rng.gen_range(1..5), target.1) }),
+            16..=17 => (OBReqType::AMEND, OBRequest { amend: AmendRequest::new(target.0, rng.gen_range(1..20), price, target.1) }),
+            18 => (OBReqType::TICK, OBRequest { tick: TickRequest::new(now, ob_id) }),
+            _ => (OBReqType::FLUSH, OBRequest { flush: FlushRequest::new(ob_id) }),
+        };
+        OBRequestWrapper { req: req, typ: typ }
+    }
+
+    // a TICK that expired nothing; the journal doesn't keep those as they come
+    fn quiet(typ: OBReqType, resps: &[OBResponseWrapper]) -> bool {
+        matches!(typ, OBReqType::TICK) && resps.len() == 1
+    }
+
+    #[test]
+    fn test_replay_matches_original() {
+        let path = journal_path("replay");
+        let journal = Journal::replay(&path, &mut Manager::new(200, BOOKS), 0).unwrap();
+        let router = Manager::new(200, BOOKS).spawn(2).journaled(journal);
+
+        // batches go out together so the workers race, later batches aim at earlier oids
+        let mut rng = StdRng::seed_from_u64(23);
+        let mut oids: Vec<(OrderId, u16)> = Vec::new();
+        let mut original: Vec<Vec<Vec<u8>>> = vec![Vec::new(); BOOKS as usize];
+        for now in 0..100 {
+            let pending: Vec<_> = (0..20)
+                .map(|_| {
+                    let req = request(&mut rng, &oids, now);
+                    (req.typ, req.ob_id().unwrap(), router.dispatch(req))
+                })
+                .collect();
+            for (typ, ob_id, rx) in pending {
+                let resps = rx.recv().unwrap();
+                for resp in resps.iter() {
+                    match resp.typ {
+                        OBRespType::ADD => oids.push((unsafe { resp.resp.add.oid }, ob_id)),
+                        OBRespType::AMEND => oids.push((unsafe { resp.resp.amend.new_oid }, ob_id)),
+                        _ => (),
+                    }
+                }
+                if !quiet(typ, &resps) {
+                    original[ob_id as usize].push(wire(&resps));
+                }
+            }
+        }
+        let views: Vec<Vec<u8>> = (0..BOOKS)
+            .map(|ob_id| wire(&router.dispatch(OBRequestWrapper {
+                req: OBRequest { level_view: LevelViewRequest::new(ob_id) },
+                typ: OBReqType::LEVELVIEW,
+            }).recv().unwrap()))
+            .collect();
+        drop(router);
+
+        // each book's records are in its dispatch order, and the same requests on a single
+        // thread give the same bytes back. quiet TICKs are left out on both sides: the ones
+        // that were journaled only carry a clock forward
+        let mut manager = Manager::new(200, BOOKS);
+        let file = File::open(&path).unwrap();
+        let mut records = 0;
+        let mut replayed: Vec<Vec<Vec<u8>>> = vec![Vec::new(); BOOKS as usize];
+        for record in Records::new(BufReader::new(file), BOOKS) {
+            let req = record.unwrap().req;
+            let resps = manager.handle(&req);
+            if !quiet(req.typ, &resps) {
+                replayed[req.ob_id().unwrap() as usize].push(wire(&resps));
+            }
+            records += 1;
+        }
+        for ob_id in 0..BOOKS as usize {
+            assert!(replayed[ob_id].len() == original[ob_id].len(), "book {} lost requests", ob_id);
+            for (i, resps) in replayed[ob_id].iter().enumerate() {
+                assert!(*resps == original[ob_id][i], "book {} request {} diverged", ob_id, i);
+            }
+        }
+
+        // and startup replay leaves the books where the router left them
+        let mut restored = Manager::new(200, BOOKS);
+        let journal = Journal::replay(&path, &mut restored, 0).unwrap();
+        assert!(journal.next_seq() == records + 1);
+        for ob_id in 0..BOOKS {
+            let view = restored.handle(&OBRequestWrapper {
+                req: OBRequest { level_view: LevelViewRequest::new(ob_id) },
+                typ: OBReqType::LEVELVIEW,
+            });
+            assert!(wire(&view) == views[ob_id as usize], "book {} diverged", ob_id);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_quiet_tick_carries_its_clock() {
+        let path = journal_path("quiet");
+        let journal = Journal::replay(&path, &mut Manager::new(10, BOOKS), 0).unwrap();
+        let router = Manager::new(10, BOOKS).spawn(2).journaled(journal);
+        let tick = |now| OBRequestWrapper { req: OBRequest { tick: TickRequest::new(now, 0) }, typ: OBReqType::TICK };
+
+        // nothing to expire, so nothing is written
+        router.dispatch(tick(5)).recv().unwrap();
+        router.dispatch(tick(10)).recv().unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() == 0);
+
+        // the clock is at 10, so this is killed as it comes in; it only replays that way
+        // if the clock gets there first
+        let add = OBRequestWrapper {
+            req: OBRequest { add: AddRequest::new(5, -30, 0, TimeInForce::GTT, false, 7) },
+            typ: OBReqType::ADD,
+        };
+        let original = router.dispatch(add).recv().unwrap();
+        assert!(matches!(original[0].typ, OBRespType::KILL));
+        drop(router);
+
+        let mut manager = Manager::new(10, BOOKS);
+        let records: Vec<OBRequestWrapper> = Records::new(BufReader::new(File::open(&path).unwrap()), BOOKS)
+            .map(|record| record.unwrap().req)
+            .collect();
+        assert!(records.len() == 2);
+        assert!(matches!(records[0].typ, OBReqType::TICK) && unsafe { records[0].req.tick.now } == 10);
+        manager.handle(&records[0]);
+        assert!(wire(&manager.handle(&records[1])) == wire(&original));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_torn_record_is_dropped() {
+        let path = journal_path("torn");
+        let add = |price| OBRequestWrapper {
+            req: OBRequest { add: AddRequest::new(5, price, 1, TimeInForce::GTC, false, 0) },
+            typ: OBReqType::ADD,
+        };
+
+        let mut journal = Journal::replay(&path, &mut Manager::new(10, BOOKS), 0).unwrap();
+        for price in [-30, -31, -32] {
+            journal.append(&add(price)).unwrap();
+        }
+        let whole = std::fs::metadata(&path).unwrap().len();
+
+        // the crash came halfway through the fourth record
+        let mut torn = 4u64.to_le_bytes().to_vec();
+        torn.extend_from_slice(&encode_request(0, &OBReqType::ADD, &add(-33).req)[..5]);
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&torn).unwrap();
+
+        let mut manager = Manager::new(10, BOOKS);
+        let mut journal = Journal::replay(&path, &mut manager, 0).unwrap();
+        assert!(journal.next_seq() == 4);
+        assert!(std::fs::metadata(&path).unwrap().len() == whole);
+        let view = unsafe { manager.handle(&OBRequestWrapper {
+            req: OBRequest { level_view: LevelViewRequest::new(1) },
+            typ: OBReqType::LEVELVIEW,
+        })[0].resp.view.prices };
+        assert!(view.iter().sum::<u64>() == 15);
+
+        // the next record picks up where the whole ones ended
+        assert!(journal.append(&add(-34)).unwrap() == 4);
+        let seqs: Vec<u64> = Records::new(BufReader::new(File::open(&path).unwrap()), BOOKS)
+            .map(|record| record.unwrap().seq)
+            .collect();
+        assert!(seqs == vec![1, 2, 3, 4]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_gap_is_refused() {
+        let path = journal_path("gap");
+        let flush = OBRequestWrapper { req: OBRequest { flush: FlushRequest::new(0) }, typ: OBReqType::FLUSH };
+        let mut bytes = Vec::new();
+        for seq in [1u64, 2, 4] {
+            bytes.extend_from_slice(&seq.to_le_bytes());
+            bytes.extend_from_slice(&encode_request(0, &flush.typ, &flush.req));
+        }
+        std::fs::write(&path, bytes).unwrap();
+
+        let err = Journal::replay(&path, &mut Manager::new(10, BOOKS), 0).err().unwrap();
+        assert!(err.kind() == ErrorKind::InvalidData);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_replay_after_snapshot() {
+        let path = journal_path("after");
+        let flush = |ob_id| OBRequestWrapper { req: OBRequest { flush: FlushRequest::new(ob_id) }, typ: OBReqType::FLUSH };
+        let mut journal = Journal::replay(&path, &mut Manager::new(10, BOOKS), 0).unwrap();
+        for ob_id in 0..3 {
+            journal.append(&flush(ob_id)).unwrap();
+        }
+
+        // the snapshot got written but the engine died before truncating
+        assert!(Journal::replay(&path, &mut Manager::new(10, BOOKS), 2).unwrap().next_seq() == 4);
+        assert!(Journal::replay(&path, &mut Manager::new(10, BOOKS), 5).unwrap().next_seq() == 6);
+
+        // truncated, then a few more; only a snapshot that got to 3 can pick up from here
+        journal.truncate().unwrap();
+        assert!(journal.append(&flush(0)).unwrap() == 4);
+        assert!(Journal::replay(&path, &mut Manager::new(10, BOOKS), 3).unwrap().next_seq() == 5);
+        assert!(Journal::replay(&path, &mut Manager::new(10, BOOKS), 0).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,38 +1,558 @@
 use crate::book::book::{Orderbook};
-use crate::book::bump::BumpAllocator;
-use std::cell::RefCell;
-use std::rc::Rc;
+use crate::comm::journal::Journal;
+use crate::comm::urcp::*;
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::ops;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Barrier, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
 
 pub struct Manager {
     books: Vec<Orderbook>
 }
 
+const SNAPSHOT_MAGIC: &[u8; 4] = b"FBSN";
+const SNAPSHOT_VERSION: u16 = 2;
+
+// magic, version u16, the last journal record the books include (u64), book count u16, then
+// every book's Orderbook::snapshot in book order
+fn snapshot_file(seq: u64, books: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = SNAPSHOT_MAGIC.to_vec();
+    out.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+    out.extend_from_slice(&seq.to_le_bytes());
+    out.extend_from_slice(&(books.len() as u16).to_le_bytes());
+    for book in books {
+        out.extend_from_slice(book);
+    }
+    out
+}
+
+// readers see the old snapshot or the new one, never half of one
+fn write_durably(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
 impl Manager {
-    pub fn new(order_capacity: usize, level_capacity: usize, book_size: u16) -> Self {
-        let arena = Rc::new(RefCell::new(BumpAllocator::with_capacity(order_capacity)));
-        let mut books: Vec<Orderbook> = Vec::with_capacity(book_size.into()); 
+    // every book gets its own arena of `order_capacity` orders
+    pub fn new(order_capacity: usize, book_size: u16) -> Self {
+        let mut books: Vec<Orderbook> = Vec::with_capacity(book_size.into());
 
-        for _ in 0..book_size {
-            books.push(Orderbook::with_capacities(Rc::clone(&arena), level_capacity));
+        for id in 0..book_size {
+            books.push(Orderbook::with_capacities(id, order_capacity));
         }
 
         Manager {
-            books: books, 
+            books: books,
+        }
+    }
+
+    // every book's orders at this moment; `seq` is the last journal record they include
+    pub fn snapshot(self: &Self, seq: u64) -> Vec<u8> {
+        let books: Vec<Vec<u8>> = self.books.iter()
+            .map(|book| {
+                let mut out = Vec::new();
+                book.snapshot(&mut out);
+                out
+            })
+            .collect();
+        snapshot_file(seq, &books)
+    }
+    // the books from a snapshot and the last journal record they include
+    pub fn restore(bytes: &[u8], order_capacity: usize) -> io::Result<(Self, u64)> {
+        let corrupt = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("bad snapshot: {}", what));
+        if bytes.len() < 16 || &bytes[..4] != SNAPSHOT_MAGIC {
+            return Err(corrupt("not a snapshot"));
+        }
+        if u16::from_le_bytes([bytes[4], bytes[5]]) != SNAPSHOT_VERSION {
+            return Err(corrupt("unknown version"));
+        }
+        let seq = u64::from_le_bytes(bytes[6..14].try_into().unwrap());
+        let book_size = u16::from_le_bytes([bytes[14], bytes[15]]);
+
+        let mut buf = &bytes[16..];
+        let mut books = Vec::with_capacity(book_size.into());
+        for id in 0..book_size {
+            let book = Orderbook::restore(&mut buf, order_capacity)?;
+            if book.id() != id {
+                return Err(corrupt("books out of order"));
+            }
+            books.push(book);
+        }
+        if !buf.is_empty() {
+            return Err(corrupt("trailing bytes"));
+        }
+
+        Ok((Manager { books: books }, seq))
+    }
+    pub fn book_size(self: &Self) -> usize {
+        self.books.len()
+    }
+    // runs a request against its book on the calling thread
+    pub fn handle(self: &mut Self, req: &OBRequestWrapper) -> Vec<OBResponseWrapper> {
+        let ob_id = req.ob_id().expect("request isn't for a book");
+        apply(&mut self.books[ob_id as usize], req)
+    }
+
+    // moves the books onto `threads` workers, book i lives on worker i % threads
+    pub fn spawn(self: Self, threads: usize) -> Router {
+        let book_size = self.books.len();
+        let threads = threads.clamp(1, self.books.len().max(1));
+        let mut shards: Vec<Vec<Orderbook>> = (0..threads).map(|_| Vec::new()).collect();
+        for (id, book) in self.books.into_iter().enumerate() {
+            shards[id % threads].push(book);
+        }
+
+        let mut workers = Vec::with_capacity(threads);
+        let mut handles = Vec::with_capacity(threads);
+        for shard in shards {
+            let (tx, rx) = channel::<Job>();
+            workers.push(tx);
+            handles.push(thread::spawn(move || work(shard, threads, rx)));
+        }
+
+        Router {
+            book_size: book_size,
+            workers: workers,
+            handles: handles,
+            journal: None,
+            snapshotting: Mutex::new(()),
         }
     }
+}
+
+// everything the engine writes back for one request, in wire order; vector replies end in DELIM
+pub fn apply(book: &mut Orderbook, req: &OBRequestWrapper) -> Vec<OBResponseWrapper> {
+    let delimited = |mut resps: Vec<OBResponseWrapper>| {
+        resps.push(OBResponseWrapper { resp: OBResponse { end: DelimResponse {} }, typ: OBRespType::DELIM });
+        resps
+    };
+    let price_result = |result| match result {
+        Ok(price) => OBResponseWrapper { resp: OBResponse { price: price }, typ: OBRespType::PRICE },
+        Err(err) => OBResponseWrapper { resp: OBResponse { error: ErrorResponse::new(err) }, typ: OBRespType::ERROR },
+    };
 
+    unsafe {
+        match req.typ {
+            OBReqType::ADD => {
+                let req = req.req.add;
+                delimited(book.match_order(req.qty, req.price, req.tif, req.post_only, req.expires_at))
+            },
+            OBReqType::MARKET => {
+                let req = req.req.market;
+                delimited(book.market_order(req.qty, req.limit))
+            },
+            OBReqType::AMEND => {
+                let req = req.req.amend;
+                delimited(match book.amend(req.oid, req.qty, req.price) {
+                    Ok(response_vec) => response_vec,
+                    Err(err) => vec![OBResponseWrapper { resp: OBResponse { error: ErrorResponse::new(err) }, typ: OBRespType::ERROR }],
+                })
+            },
+            OBReqType::TICK => delimited(book.advance_clock(req.req.tick.now)),
+            OBReqType::CANCEL => vec![price_result(book.delete(req.req.cancel.oid))],
+            OBReqType::REDUCE => {
+                let req = req.req.reduce;
+                vec![price_result(book.reduce(req.oid, req.qty))]
+            },
+            OBReqType::STATUS => vec![match book.order_status(req.req.status.oid) {
+                Ok(status) => OBResponseWrapper { resp: OBResponse { status: status }, typ: OBRespType::STATUS },
+                Err(err) => OBResponseWrapper { resp: OBResponse { error: ErrorResponse::new(err) }, typ: OBRespType::ERROR },
+            }],
+            OBReqType::ORDERS => delimited(book.live_orders().into_iter()
+                .map(|status| OBResponseWrapper { resp: OBResponse { status: status }, typ: OBRespType::STATUS })
+                .collect()),
+            OBReqType::LEVELVIEW => vec![OBResponseWrapper {
+                resp: OBResponse { view: PriceViewResponse::new(book.get_level_view()).boxed() },
+                typ: OBRespType::LEVELVIEW,
+            }],
+            OBReqType::FLUSH => {
+                book.clear();
+                delimited(Vec::new())
+            },
+            _ => unreachable!("not a book request"),
+        }
+    }
+}
+
+enum Job {
+    Request {
+        req: OBRequestWrapper,
+        reply: Sender<Vec<OBResponseWrapper>>,
+    },
+    // every book on the worker, as (ob_id, Orderbook::snapshot); the worker then waits on
+    // the barrier, so it runs nothing until the snapshot is written
+    Snapshot(Sender<(usize, Vec<u8>)>, Arc<Barrier>),
+    // from here on replies go through the journal
+    Journal(Sender<Commit>),
+}
+
+fn work(mut books: Vec<Orderbook>, threads: usize, jobs: Receiver<Job>) {
+    let mut journal: Option<Sender<Commit>> = None;
+    // the clock of a book whose last TICKs expired nothing. those aren't journaled, the
+    // book's next record is preceded by one that moves the clock there instead
+    let mut quiet_ticks: Vec<Option<u64>> = vec![None; books.len()];
+
+    // jobs come off the channel in the order they were dispatched, so a book sees its
+    // requests in order; nothing is promised between books on different workers
+    for job in jobs {
+        match job {
+            Job::Request { req, reply } => {
+                let ob_id = req.ob_id().unwrap();
+                let idx = ob_id as usize / threads;
+                let resps = apply(&mut books[idx], &req);
+                let journal = match &journal {
+                    Some(journal) => journal,
+                    None => {
+                        // the caller may have hung up, the book still took the request
+                        let _ = reply.send(resps);
+                        continue;
+                    },
+                };
+
+                let mut records = Vec::new();
+                if matches!(req.typ, OBReqType::TICK) && resps.len() == 1 {
+                    quiet_ticks[idx] = Some(unsafe { req.req.tick.now });
+                } else if req.typ.changes_book() {
+                    if let Some(now) = quiet_ticks[idx].take() {
+                        records.push(OBRequestWrapper { req: OBRequest { tick: TickRequest::new(now, ob_id) }, typ: OBReqType::TICK });
+                    }
+                    records.push(req);
+                }
+                // reads go through as well, so nothing is answered ahead of a write it saw
+                let _ = journal.send(Commit::Reply { records: records, resps: resps, reply: reply });
+            },
+            Job::Snapshot(reply, barrier) => {
+                for book in books.iter() {
+                    let mut out = Vec::new();
+                    book.snapshot(&mut out);
+                    let _ = reply.send((book.id() as usize, out));
+                }
+                drop(reply);
+                barrier.wait();
+            },
+            Job::Journal(commits) => journal = Some(commits),
+        }
+    }
+}
+
+// what the workers hand the journal thread
+enum Commit {
+    // the records a request left, if any, and its reply, held until they're on disk
+    Reply {
+        records: Vec<OBRequestWrapper>,
+        resps: Vec<OBResponseWrapper>,
+        reply: Sender<Vec<OBResponseWrapper>>,
+    },
+    // every book, taken while the workers wait; `done` gets the last record they include
+    Snapshot {
+        path: PathBuf,
+        books: Vec<Vec<u8>>,
+        done: Sender<io::Result<u64>>,
+    },
+}
+
+// a busy engine still answers at least this often
+const MAX_BATCH: usize = 1024;
+
+// a request that ran but can't be journaled can't be replayed, nothing may answer after it
+fn stop(e: io::Error) -> ! {
+    println!("JOURNAL: {}, stopping the engine", e);
+    std::process::abort();
+}
+
+// one sync for everything written since the last, then the replies that were waiting on it
+fn release(journal: &mut Journal, held: &mut Vec<(Sender<Vec<OBResponseWrapper>>, Vec<OBResponseWrapper>)>) {
+    if let Err(e) = journal.sync() {
+        stop(e);
+    }
+    for (reply, resps) in held.drain(..) {
+        let _ = reply.send(resps);
+    }
+}
+
+// group commit: records are written as they come and synced once the queue runs dry, so
+// the books share the cost of a sync instead of taking turns at it
+fn commit(mut journal: Journal, commits: Receiver<Commit>) {
+    let mut held = Vec::new();
+    loop {
+        if held.len() >= MAX_BATCH {
+            release(&mut journal, &mut held);
+        }
+        let commit = match commits.try_recv() {
+            Ok(commit) => commit,
+            Err(TryRecvError::Empty) => {
+                release(&mut journal, &mut held);
+                match commits.recv() {
+                    Ok(commit) => commit,
+                    Err(_) => break,
+                }
+            },
+            Err(TryRecvError::Disconnected) => break,
+        };
+        match commit {
+            Commit::Reply { records, resps, reply } => {
+                for record in records.iter() {
+                    if let Err(e) = journal.append(record) {
+                        stop(e);
+                    }
+                }
+                held.push((reply, resps));
+            },
+            Commit::Snapshot { path, books, done } => {
+                release(&mut journal, &mut held);
+                let seq = journal.next_seq() - 1;
+                let written = write_durably(&path, &snapshot_file(seq, &books)).and_then(|_| journal.truncate());
+                let _ = done.send(written.map(|_| seq));
+            },
+        }
+    }
+    release(&mut journal, &mut held);
+}
+
+// Front of the threaded engine
+//
+// requests for the same book run in dispatch order; requests for different books run
+// concurrently and can finish in any order. callers that need replies in request order
+// keep the receivers in a queue and drain it in order
+pub struct Router {
+    book_size: usize,
+    workers: Vec<Sender<Job>>,
+    handles: Vec<JoinHandle<()>>,
+    journal: Option<(Sender<Commit>, JoinHandle<()>)>,
+    // one snapshot at a time
+    snapshotting: Mutex<()>,
+}
+
+impl Router {
+    // every request that changed a book is journaled once it has run, and nothing is
+    // answered until the records before it are on disk. the journal should already hold
+    // whatever the books were rebuilt from
+    pub fn journaled(mut self: Self, journal: Journal) -> Self {
+        let (tx, rx) = channel();
+        for worker in self.workers.iter() {
+            worker.send(Job::Journal(tx.clone())).expect("engine worker died");
+        }
+        self.journal = Some((tx, thread::spawn(move || commit(journal, rx))));
+        self
+    }
+    pub fn book_size(self: &Self) -> usize {
+        self.book_size
+    }
+    pub fn dispatch(self: &Self, req: OBRequestWrapper) -> Receiver<Vec<OBResponseWrapper>> {
+        let ob_id = req.ob_id().expect("request isn't for a book") as usize;
+        assert!(ob_id < self.book_size, "no book {}", ob_id);
+
+        let (tx, rx) = channel();
+        self.workers[ob_id % self.workers.len()]
+            .send(Job::Request { req: req, reply: tx })
+            .expect("engine worker died");
+        rx
+    }
+    // writes every book to `path` and truncates the journal, returns the last journal record
+    // the snapshot includes. the workers wait until it's written, so the books all stop at
+    // the same record; without a journal it's 0
+    pub fn snapshot<P: AsRef<Path>>(self: &Self, path: P) -> io::Result<u64> {
+        let _snapshotting = self.snapshotting.lock().unwrap();
+
+        // queued behind everything already dispatched
+        let barrier = Arc::new(Barrier::new(self.workers.len() + 1));
+        let (tx, rx) = channel();
+        for worker in self.workers.iter() {
+            worker.send(Job::Snapshot(tx.clone(), Arc::clone(&barrier))).expect("engine worker died");
+        }
+        drop(tx);
+        let mut books = vec![Vec::new(); self.book_size];
+        for (ob_id, book) in rx {
+            books[ob_id] = book;
+        }
+
+        // the records the books include were sent before them, so they're ahead of this
+        let result = match &self.journal {
+            Some((commits, _)) => {
+                let (done, written) = channel();
+                commits.send(Commit::Snapshot { path: path.as_ref().to_path_buf(), books: books, done: done }).expect("engine journal died");
+                written.recv().expect("engine journal died")
+            },
+            None => write_durably(path.as_ref(), &snapshot_file(0, &books)).map(|_| 0),
+        };
+        barrier.wait();
+        result
+    }
+}
+
+impl Drop for Router {
+    fn drop(&mut self) {
+        // closing the queues lets the workers finish what's queued and exit
+        self.workers.clear();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+        // then the journal writes and syncs whatever they left it
+        if let Some((commits, handle)) = self.journal.take() {
+            drop(commits);
+            let _ = handle.join();
+        }
+    }
 }
 
 impl ops::Index<usize> for Manager {
     type Output = Orderbook;
-    fn index<'a>(&'a self, i: usize) -> &'a Orderbook {
+    fn index(&self, i: usize) -> &Orderbook {
         &self.books[i]
     }
 }
 
 impl ops::IndexMut<usize> for Manager {
-    fn index_mut<'a>(&'a mut self, i: usize) -> &'a mut Orderbook {
+    fn index_mut(&mut self, i: usize) -> &mut Orderbook {
         &mut self.books[i]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::book::TimeInForce;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const BOOKS: u16 = 5;
+
+    // (kind, book, qty, price) so the same request can be built for both engines
+    fn request(kind: u8, ob_id: u16, qty: u64, price: i8) -> OBRequestWrapper {
+        match kind {
+            0 => OBRequestWrapper {
+                req: OBRequest { add: AddRequest::new(qty, price, ob_id, TimeInForce::GTC, false, 0) },
+                typ: OBReqType::ADD,
+            },
+            1 => OBRequestWrapper {
+                req: OBRequest { market: MarketRequest::new(qty, price, ob_id) },
+                typ: OBReqType::MARKET,
+            },
+            _ => OBRequestWrapper {
+                req: OBRequest { level_view: LevelViewRequest::new(ob_id) },
+                typ: OBReqType::LEVELVIEW,
+            },
+        }
+    }
+
+    fn requests() -> Vec<(u8, u16, u64, i8)> {
+        let mut rng = StdRng::seed_from_u64(14);
+        (0..3000).map(|_| {
+            let price = rng.gen_range(1..99) * if rng.gen_bool(0.5) { -1 } else { 1 };
+            (rng.gen_range(0..3), rng.gen_range(0..BOOKS), rng.gen_range(1..20), price)
+        }).collect()
+    }
+
+    #[test]
+    fn test_router_matches_single_thread() {
+        let requests = requests();
+
+        let mut manager = Manager::new(1000, BOOKS);
+        let expected: Vec<String> = requests.iter()
+            .map(|(kind, ob_id, qty, price)| format!("{:?}", manager.handle(&request(*kind, *ob_id, *qty, *price))))
+            .collect();
+
+        // uneven sharding on purpose, and everything is queued before anything is read back
+        // so the workers race each other
+        let router = Manager::new(1000, BOOKS).spawn(3);
+        let pending: Vec<Receiver<Vec<OBResponseWrapper>>> = requests.iter()
+            .map(|(kind, ob_id, qty, price)| router.dispatch(request(*kind, *ob_id, *qty, *price)))
+            .collect();
+
+        for (i, rx) in pending.into_iter().enumerate() {
+            assert!(format!("{:?}", rx.recv().unwrap()) == expected[i], "request {} diverged", i);
+        }
+    }
+
+    #[test]
+    fn test_router_keeps_book_order() {
+        let router = Manager::new(100, 2).spawn(2);
+
+        // a flush queued between adds on one book only clears what came before it
+        let first = router.dispatch(request(0, 0, 5, 50));
+        let other = router.dispatch(request(0, 1, 7, 50));
+        let flush = router.dispatch(OBRequestWrapper {
+            req: OBRequest { flush: FlushRequest::new(0) },
+            typ: OBReqType::FLUSH,
+        });
+        let second = router.dispatch(request(0, 0, 3, 50));
+        let view = router.dispatch(request(2, 0, 0, 0));
+        let other_view = router.dispatch(request(2, 1, 0, 0));
+
+        for rx in [first, other, flush, second] {
+            rx.recv().unwrap();
+        }
+        let view = unsafe { view.recv().unwrap()[0].resp.view.prices };
+        let other_view = unsafe { other_view.recv().unwrap()[0].resp.view.prices };
+        assert!(view[150] == 3);
+        assert!(other_view[150] == 7);
+    }
+
+    #[test]
+    fn test_router_snapshot_and_journal_restore() {
+        let dir = std::env::temp_dir();
+        let journal_path = dir.join(format!("fish-router-{}.journal", std::process::id()));
+        let snapshot_path = dir.join(format!("fish-router-{}.snapshot", std::process::id()));
+        let _ = std::fs::remove_file(&journal_path);
+
+        let requests = requests();
+        let (before, after) = requests.split_at(requests.len() / 2);
+        let changes = |requests: &[(u8, u16, u64, i8)]| requests.iter().filter(|(kind, ..)| *kind != 2).count() as u64;
+
+        let journal = Journal::replay(&journal_path, &mut Manager::new(1000, BOOKS), 0).unwrap();
+        let router = Manager::new(1000, BOOKS).spawn(3).journaled(journal);
+        let run = |requests: &[(u8, u16, u64, i8)]| {
+            let pending: Vec<_> = requests.iter()
+                .map(|(kind, ob_id, qty, price)| router.dispatch(request(*kind, *ob_id, *qty, *price)))
+                .collect();
+            pending.into_iter().for_each(|rx| { rx.recv().unwrap(); });
+        };
+        run(before);
+        // the snapshot holds every journaled request, so the journal starts over
+        assert!(router.snapshot(&snapshot_path).unwrap() == changes(before));
+        assert!(std::fs::metadata(&journal_path).unwrap().len() == 0);
+        run(after);
+        drop(router);
+
+        let (mut restored, seq) = Manager::restore(&std::fs::read(&snapshot_path).unwrap(), 1000).unwrap();
+        assert!(seq == changes(before));
+        let journal = Journal::replay(&journal_path, &mut restored, seq).unwrap();
+        assert!(journal.next_seq() == changes(&requests) + 1);
+
+        // matches a single thread that saw everything, and keeps matching
+        let mut straight = Manager::new(1000, BOOKS);
+        for (kind, ob_id, qty, price) in requests.iter() {
+            straight.handle(&request(*kind, *ob_id, *qty, *price));
+        }
+        for (kind, ob_id, qty, price) in requests.iter().take(500) {
+            let req = request(*kind, *ob_id, *qty, *price);
+            assert!(format!("{:?}", restored.handle(&req)) == format!("{:?}", straight.handle(&req)));
+        }
+        let _ = std::fs::remove_file(&journal_path);
+        let _ = std::fs::remove_file(&snapshot_path);
+    }
+
+    #[test]
+    fn test_restore_refuses_foreign_files() {
+        let snapshot = Manager::new(10, 2).snapshot(7);
+        assert!(Manager::restore(&snapshot, 10).unwrap().1 == 7);
+        assert!(Manager::restore(b"FBSN", 10).is_err());
+        assert!(Manager::restore(&snapshot[..snapshot.len() - 1], 10).is_err());
+        let mut trailing = snapshot.clone();
+        trailing.push(0);
+        assert!(Manager::restore(&trailing, 10).is_err());
+        let mut newer = snapshot.clone();
+        newer[4] = SNAPSHOT_VERSION as u8 + 1;
+        assert!(Manager::restore(&newer, 10).is_err());
+    }
+}
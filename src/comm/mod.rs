@@ -1,5 +1,10 @@
 pub mod manager;
+pub mod journal;
+pub mod replay;
+pub mod script;
+pub mod server;
 pub mod urcp;
+pub mod codec;
 pub mod client;
 pub mod stream;
 pub mod repo;
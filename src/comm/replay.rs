@@ -0,0 +1,207 @@
+use crate::comm::journal::Records;
+use crate::comm::manager::Manager;
+use crate::comm::script::parse_script;
+use crate::comm::urcp::*;
+
+use std::io::{Error, ErrorKind, Read, Result};
+
+// Running recorded requests again
+//
+// a journal or an order script goes through books in this process or a running engine, and
+// comes out as one line per request followed by one per response. two runs of the same
+// requests match line for line, which is what --diff in the replay tool checks
+
+// where the requests go
+pub enum Target {
+    Local(Manager),
+    // already past the handshake
+    Engine(Box<dyn Transport>),
+}
+
+impl Target {
+    pub fn run(self: &mut Self, corr: u32, req: &OBRequestWrapper) -> Result<Vec<OBResponseWrapper>> {
+        match self {
+            Target::Local(manager) => Ok(manager.handle(req)),
+            Target::Engine(stream) => {
+                write_request(stream, corr, &req.typ, &req.req)?;
+                if req.typ.replies_with_vec() {
+                    read_response_vec(stream)
+                } else {
+                    Ok(vec![read_response(stream)?])
+                }
+            },
+        }
+    }
+}
+
+// one line per response; delimiters are left out so local and engine runs read the same
+pub fn describe(resp: &OBResponseWrapper) -> Option<String> {
+    match resp.typ {
+        OBRespType::DELIM => None,
+        OBRespType::LEVELVIEW => {
+            let view = unsafe { &resp.resp.view };
+            let levels: Vec<String> = view.prices.iter().enumerate()
+                .filter(|(_, qty)| **qty > 0)
+                .map(|(idx, qty)| if idx < 100 {
+                    format!("YES $0.{} @ {}", idx, qty)
+                } else {
+                    format!("NO $0.{} @ {}", idx - 100, qty)
+                })
+                .collect();
+            Some(format!("LevelView [{}]", levels.join(", ")))
+        },
+        _ => Some(format!("{:?}", resp)),
+    }
+}
+
+// (record, request) for every journal record after `skip_through`, the last one the books
+// already have
+pub fn journal_requests<R: Read>(reader: R, books: u16, skip_through: u64) -> Result<Vec<(String, OBRequestWrapper)>> {
+    let mut reqs = Vec::new();
+    for record in Records::new(reader, books) {
+        let record = record?;
+        if record.seq <= skip_through {
+            continue;
+        }
+        // a journal truncated behind a snapshot only makes sense on top of it
+        if reqs.is_empty() && record.seq != skip_through + 1 {
+            return Err(Error::new(ErrorKind::InvalidData, format!(
+                "journal resumes at record {} but the books stop at {}, replay it with --snapshot",
+                record.seq, skip_through,
+            )));
+        }
+        reqs.push((format!("#{}", record.seq), record.req));
+    }
+    Ok(reqs)
+}
+
+// (line, request) for every request in a script
+pub fn script_requests(text: &str, books: u16) -> Result<Vec<(String, OBRequestWrapper)>> {
+    let reqs = parse_script(text, books).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    Ok(reqs.into_iter().map(|(line, req)| (format!("line {}", line), req)).collect())
+}
+
+// runs the requests in order, each one's line followed by what it produced
+pub fn run(target: &mut Target, reqs: &[(String, OBRequestWrapper)]) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    for (corr, (from, req)) in reqs.iter().enumerate() {
+        lines.push(format!("[{}] {:?}", from, req));
+        for resp in target.run(corr as u32 + 1, req)?.iter() {
+            if let Some(event) = describe(resp) {
+                lines.push(format!("    {}", event));
+            }
+        }
+    }
+    Ok(lines)
+}
+
+// where `got` and the expected lines part ways, the first `limit` mismatches in full and a
+// count at the end; true when they match
+pub fn diff(expected: &str, got: &[String], limit: usize) -> (Vec<String>, bool) {
+    let expected: Vec<&str> = expected.lines().collect();
+    let mut report = Vec::new();
+    let mut mismatches = 0;
+    for i in 0..expected.len().max(got.len()) {
+        let (want, have) = (expected.get(i).copied(), got.get(i).map(|line| line.as_str()));
+        if want == have {
+            continue;
+        }
+        mismatches += 1;
+        if mismatches <= limit {
+            report.push(format!("line {}:", i + 1));
+            report.push(format!("- {}", want.unwrap_or("<nothing>")));
+            report.push(format!("+ {}", have.unwrap_or("<nothing>")));
+        }
+    }
+    if mismatches == 0 {
+        report.push(format!("all {} lines match", got.len()));
+    } else {
+        report.push(format!("{} of {} lines differ", mismatches, expected.len().max(got.len())));
+    }
+    (report, mismatches == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comm::server::serve;
+    use crate::comm::stream::handshake;
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+    use std::thread;
+
+    const BOOKS: u16 = 2;
+
+    // oids are (book << 48) | slot with generation 0 this early, so the script can aim at them
+    const SCRIPT: &str = "
+        A 5 -30 0
+        A 3 20 0 # rests, doesn't cross
+        A 4 -40 1 T50
+        A 2 60 0 G P # post only, would cross
+        M 4 80 0
+        Q 0 0
+        U 1 2 25 0
+        R 0 1 0
+        O 0
+        O 1
+        T 60 1 # expires the GTT
+        T 70 1
+        A 7 -10 1 I
+        C 1 0
+        C 1 0 # already gone
+        V 0
+        V 1
+        F 1
+        V 1
+    ";
+
+    #[test]
+    fn test_local_and_engine_runs_match() {
+        let reqs = script_requests(SCRIPT, BOOKS).unwrap();
+        let local = run(&mut Target::Local(Manager::new(100, BOOKS)), &reqs).unwrap();
+
+        let router = Arc::new(Manager::new(100, BOOKS).spawn(2));
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let reader = server.try_clone().unwrap();
+        let served = thread::spawn(move || serve(router, reader, server));
+        handshake(&mut client).unwrap();
+        let engine = run(&mut Target::Engine(Box::new(client)), &reqs).unwrap();
+
+        let (report, matched) = diff(&local.join("\n"), &engine, 20);
+        assert!(matched, "{}", report.join("\n"));
+        // every request, and more than just its own line
+        assert!(local.iter().filter(|line| line.starts_with('[')).count() == reqs.len());
+        assert!(local.len() > reqs.len() * 2);
+        // the client hung up with its Target
+        served.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_diff_reports_mismatches() {
+        let got = vec![String::from("a"), String::from("c")];
+        let (report, matched) = diff("a\nb\nd", &got, 20);
+        assert!(!matched);
+        assert!(report == vec!["line 2:", "- b", "+ c", "line 3:", "- d", "+ <nothing>", "2 of 3 lines differ"]);
+        assert!(diff("a\nc", &got, 20).1);
+    }
+
+    #[test]
+    fn test_truncated_journal_needs_its_snapshot() {
+        // what's left of a journal after a snapshot at record 3
+        let flush = OBRequestWrapper { req: OBRequest { flush: FlushRequest::new(0) }, typ: OBReqType::FLUSH };
+        let mut bytes = Vec::new();
+        for seq in [4u64, 5] {
+            bytes.extend_from_slice(&seq.to_le_bytes());
+            bytes.extend_from_slice(&encode_request(0, &flush.typ, &flush.req));
+        }
+
+        let err = journal_requests(&bytes[..], BOOKS, 0).err().unwrap();
+        assert!(err.kind() == ErrorKind::InvalidData);
+        assert!(err.to_string().contains("--snapshot"));
+
+        let reqs = journal_requests(&bytes[..], BOOKS, 3).unwrap();
+        assert!(reqs.iter().map(|(from, _)| from.as_str()).collect::<Vec<_>>() == vec!["#4", "#5"]);
+        // a snapshot that got further than the journal has nothing left to replay
+        assert!(journal_requests(&bytes[..], BOOKS, 5).unwrap().is_empty());
+    }
+}
@@ -1,9 +1,15 @@
 use std::io;
 use rusqlite::{Connection,Result,params};
 use crate::comm::domain::*;
+use crate::book::book::OrderId;
 
 const USER_BALANCE_DEFAULT: i32 = 10000;
 
+// user_orders.id is a signed 64-bit integer; an oid from book 32768 or up doesn't fit in one
+fn sql_oid(oid: OrderId) -> Result<i64> {
+    i64::try_from(oid).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
 // TODO(nw)
 //
 // Mutex this alongside comm/client::Inner so that 
@@ -44,11 +50,21 @@ pub struct InnerRepo {
 //  qty INT NOT NULL
 // );
 
+// -- qty orders lost while the engine was out of reach, settled by hand
+// CREATE TABLE IF NOT EXISTS order_gaps (
+//  id SERIAL PRIMARY KEY,
+//  oid INT NOT NULL, -- the user_orders id it came off
+//  book_id INT NOT NULL,
+//  price INT NOT NULL,
+//  qty INT NOT NULL, -- what went missing, never refunded
+//  user_fk INT NOT NULL REFERENCES users(id)
+// );
+
 impl InnerRepo {
     pub fn new() -> io::Result<Self> {
         let con: Connection = match Connection::open("ftx.db") {
             Ok(con) => io::Result::Ok(con),
-            Err(e) => io::Result::Err(io::Error::new(io::ErrorKind::Other, e)),
+            Err(e) => io::Result::Err(io::Error::other(e)),
         }?;
 
         match con.execute_batch(
@@ -72,10 +88,18 @@ impl InnerRepo {
                 book_id INT NOT NULL,
                 qty INT NOT NULL
              );
+             CREATE TABLE IF NOT EXISTS order_gaps (
+                id INTEGER PRIMARY KEY,
+                oid INTEGER NOT NULL,
+                book_id INT NOT NULL,
+                price INT NOT NULL,
+                qty INT NOT NULL,
+                user_fk INT NOT NULL REFERENCES users(id)
+             );
              COMMIT;",
         ) {
             Ok(_) => io::Result::Ok(()),
-            Err(e) => io::Result::Err(io::Error::new(io::ErrorKind::Other, e)),
+            Err(e) => io::Result::Err(io::Error::other(e)),
         }?;
 
         let _ = con.execute(
@@ -124,13 +148,23 @@ impl InnerRepo {
         )?;
         Ok(())
     }
+    // take amt out of the balance if it covers it, in one statement so two orders can't both
+    // spend the same balance; false when it doesn't cover it
+    // UPDATE users SET balance = balance - ?2 WHERE id = ?1 AND balance >= ?2;
+    pub fn reserve_balance(&mut self, uid: i32, amt: i32) -> Result<bool> {
+        let changed = self.con.execute(
+            "UPDATE users SET balance = balance - ?2 WHERE id = ?1 AND balance >= ?2",
+            (&uid, &amt),
+        )?;
+        Ok(changed == 1)
+    }
     // add order to order table referencing user id (should have) (Add order msg)
     // INSERT INTO user_orders (id,book_id, price, qty, user_fk) VALUES (?1, ?2, ?3, ?4, ?5); 
     // -- ?1 is just the oid
-    pub fn add_order_to_user(&mut self, oid: usize, book_id: u16, price: i8, qty: u64, uid: i32) -> Result<()> {
+    pub fn add_order_to_user(&mut self, oid: OrderId, book_id: u16, price: i8, qty: u64, uid: i32) -> Result<()> {
         self.con.execute(
             "INSERT INTO user_orders (id, book_id, price, qty, user_fk) VALUES (?1, ?2, ?3, ?4, ?5)",
-            (&(oid as i32), &(book_id as i32), &(price as i32), &(qty as i32), &uid),
+            (&sql_oid(oid)?, &(book_id as i32), &(price as i32), &(qty as i32), &uid),
         )?;
         Ok(())
     }
@@ -144,10 +178,10 @@ impl InnerRepo {
     // 2.) INSERT INTO contracts (user_no_fk, user_yes_fk, qty) VALUES (?1, ?2, ?3); -- qty given
     //   by OBResponse.execute yes and no you determine
     // 3.) UPDATE orders SET qty = qty - ?2 WHERE id = ?1; -- ?1 is oid
-    pub fn create_contract(&mut self, user_uid: i32, other_oid: usize, qty: u64, book_id: u16) -> Result<()> {
+    pub fn create_contract(&mut self, user_uid: i32, other_oid: OrderId, qty: u64, book_id: u16) -> Result<()> {
         let other_order: Result<UserOrder> = self.con.query_row_and_then(
             "SELECT * FROM user_orders WHERE id = ?1",
-            params![&(other_oid as i32)],
+            params![&sql_oid(other_oid)?],
             |row| {
                 Ok(UserOrder{
                     id: row.get(0)?,
@@ -170,7 +204,7 @@ impl InnerRepo {
 
         self.con.execute(
             "UPDATE user_orders SET qty = qty - ?2 WHERE id = ?1",
-            (&(other_oid as i32), &qty),
+            (&sql_oid(other_oid)?, &qty),
         )?;
 
         Ok(())
@@ -208,10 +242,10 @@ impl InnerRepo {
         Ok(())
     }
 
-    pub fn get_order(&mut self, oid: usize) -> Result<UserOrder> {
+    pub fn get_order(&mut self, oid: OrderId) -> Result<UserOrder> {
         self.con.query_row_and_then(
             "SELECT * FROM user_orders WHERE id = ?1",
-            params![&(oid as i32)],
+            params![&sql_oid(oid)?],
             |row| Ok(UserOrder{
                 id: row.get(0)?,
                 book_id: row.get(1)?,
@@ -222,10 +256,28 @@ impl InnerRepo {
         )
     }
 
-    pub fn delete_order(&mut self, oid: usize) -> Result<usize> {
+    // qty an order lost on the engine without us seeing where it went; kept across flushes
+    // until someone settles it
+    // INSERT INTO order_gaps (oid, book_id, price, qty, user_fk) VALUES (?1, ?2, ?3, ?4, ?5);
+    pub fn flag_order_gap(&mut self, order: &UserOrder, qty: u64) -> Result<()> {
+        self.con.execute(
+            "INSERT INTO order_gaps (oid, book_id, price, qty, user_fk) VALUES (?1, ?2, ?3, ?4, ?5)",
+            (&order.id, &order.book_id, &order.price, &(qty as i32), &order.user_fk),
+        )?;
+        Ok(())
+    }
+
+    pub fn set_order_qty(&mut self, oid: OrderId, qty: u64) -> Result<usize> {
+        self.con.execute(
+            "UPDATE user_orders SET qty = ?2 WHERE id = ?1",
+            (&sql_oid(oid)?, &(qty as i32)),
+        )
+    }
+
+    pub fn delete_order(&mut self, oid: OrderId) -> Result<usize> {
         self.con.execute(
             "DELETE FROM user_orders WHERE id = ?1",
-            params![&(oid as i32)],
+            params![&sql_oid(oid)?],
         )
     }
 
@@ -241,7 +293,7 @@ impl InnerRepo {
             user_fk: row.get(4)?,
         }))?;
 
-        let ret: Vec<UserOrder> = rows.into_iter().map(|x| x.unwrap_or_else(|_| UserOrder{
+        let ret: Vec<UserOrder> = rows.into_iter().map(|x| x.unwrap_or(UserOrder{
             book_id: -1,
             id: -1,
             price: -1,
@@ -264,7 +316,7 @@ impl InnerRepo {
             user_fk: row.get(4)?,
         }))?;
 
-        let ret: Vec<UserOrder> = rows.into_iter().map(|x| x.unwrap_or_else(|_| UserOrder{
+        let ret: Vec<UserOrder> = rows.into_iter().map(|x| x.unwrap_or(UserOrder{
             book_id: -1,
             id: -1,
             price: -1,
@@ -0,0 +1,162 @@
+use crate::book::book::TimeInForce;
+use crate::comm::urcp::*;
+
+// Scripted order flow, one request per line in the test REPL's syntax
+//
+//   A qty price book [I|F|G|T<expiry>] [P]   add; GTC unless IOC, FOK or GTT, P for post only
+//   M qty limit book                         market order
+//   T now book                               advance the book clock
+//   U oid qty price book                     amend
+//   C oid book                               cancel
+//   R oid qty book                           reduce
+//   Q oid book                               order status
+//   O book                                   every resting order
+//   F book                                   flush
+//   V book                                   level view
+//
+// blank lines and anything after a # are ignored. requests are checked the way the engine
+// checks them off the wire, so a script can't do anything a client couldn't
+pub fn parse_line(line: &str, books: u16) -> Result<Option<OBRequestWrapper>, String> {
+    let line = line.split('#').next().unwrap_or("");
+    let mut fields = line.split_whitespace();
+    let cmd = match fields.next() {
+        Some(cmd) => cmd,
+        None => return Ok(None),
+    };
+    let args: Vec<&str> = fields.collect();
+
+    fn arg<T: std::str::FromStr>(args: &[&str], i: usize, what: &str) -> Result<T, String> {
+        match args.get(i) {
+            Some(arg) => arg.parse::<T>().map_err(|_| format!("bad {} '{}'", what, arg)),
+            None => Err(format!("missing {}", what)),
+        }
+    }
+    let arity = |min: usize, max: usize| {
+        if args.len() < min || args.len() > max {
+            Err(format!("{} takes {} arguments, got {}", cmd, if min == max { min.to_string() } else { format!("{} to {}", min, max) }, args.len()))
+        } else {
+            Ok(())
+        }
+    };
+
+    let (typ, req) = match cmd {
+        "A" => {
+            arity(3, 5)?;
+            let (tif, expires_at) = match args.get(3) {
+                None | Some(&"G") => (TimeInForce::GTC, 0),
+                Some(&"I") => (TimeInForce::IOC, 0),
+                Some(&"F") => (TimeInForce::FOK, 0),
+                Some(tif) if tif.starts_with('T') => (TimeInForce::GTT, tif[1..].parse::<u64>().map_err(|_| format!("bad expiry '{}'", tif))?),
+                Some(tif) => return Err(format!("bad time in force '{}'", tif)),
+            };
+            let post_only = match args.get(4) {
+                None => false,
+                Some(&"P") => true,
+                Some(flag) => return Err(format!("bad flag '{}'", flag)),
+            };
+            let req = AddRequest::new(arg(&args, 0, "qty")?, arg(&args, 1, "price")?, arg(&args, 2, "book")?, tif, post_only, expires_at);
+            (OBReqType::ADD, OBRequest { add: req })
+        },
+        "M" => {
+            arity(3, 3)?;
+            (OBReqType::MARKET, OBRequest { market: MarketRequest::new(arg(&args, 0, "qty")?, arg(&args, 1, "limit")?, arg(&args, 2, "book")?) })
+        },
+        "T" => {
+            arity(2, 2)?;
+            (OBReqType::TICK, OBRequest { tick: TickRequest::new(arg(&args, 0, "time")?, arg(&args, 1, "book")?) })
+        },
+        "U" => {
+            arity(4, 4)?;
+            let req = AmendRequest::new(arg(&args, 0, "oid")?, arg(&args, 1, "qty")?, arg(&args, 2, "price")?, arg(&args, 3, "book")?);
+            (OBReqType::AMEND, OBRequest { amend: req })
+        },
+        "C" => {
+            arity(2, 2)?;
+            (OBReqType::CANCEL, OBRequest { cancel: CancelRequest::new(arg(&args, 0, "oid")?, arg(&args, 1, "book")?) })
+        },
+        "R" => {
+            arity(3, 3)?;
+            (OBReqType::REDUCE, OBRequest { reduce: ReduceRequest::new(arg(&args, 0, "oid")?, arg(&args, 1, "qty")?, arg(&args, 2, "book")?) })
+        },
+        "Q" => {
+            arity(2, 2)?;
+            (OBReqType::STATUS, OBRequest { status: StatusRequest::new(arg(&args, 0, "oid")?, arg(&args, 1, "book")?) })
+        },
+        "O" => {
+            arity(1, 1)?;
+            (OBReqType::ORDERS, OBRequest { orders: OrdersRequest::new(arg(&args, 0, "book")?) })
+        },
+        "F" => {
+            arity(1, 1)?;
+            (OBReqType::FLUSH, OBRequest { flush: FlushRequest::new(arg(&args, 0, "book")?) })
+        },
+        "V" => {
+            arity(1, 1)?;
+            (OBReqType::LEVELVIEW, OBRequest { level_view: LevelViewRequest::new(arg(&args, 0, "book")?) })
+        },
+        _ => return Err(format!("unknown command '{}'", cmd)),
+    };
+
+    // round trip through the wire format for the engine's own checks
+    let frame = Frame::from_wire(&encode_request(0, &typ, &req));
+    decode_request(&frame, books).map(Some).map_err(|err| err.to_string())
+}
+
+// every request in a script with its line number, or the first line that isn't one
+pub fn parse_script(text: &str, books: u16) -> Result<Vec<(usize, OBRequestWrapper)>, String> {
+    let mut reqs = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        match parse_line(line, books) {
+            Ok(Some(req)) => reqs.push((i + 1, req)),
+            Ok(None) => (),
+            Err(e) => return Err(format!("line {}: {}", i + 1, e)),
+        }
+    }
+    Ok(reqs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lines() {
+        let add = parse_line("A 5 -30 1 T120 P", 2).unwrap().unwrap();
+        let req = unsafe { add.req.add };
+        assert!(matches!(add.typ, OBReqType::ADD));
+        assert!(req.qty == 5 && req.price == -30 && req.ob_id == 1);
+        assert!(req.tif == TimeInForce::GTT && req.expires_at == 120 && req.post_only);
+
+        let amend = parse_line("  U 7 3 45 0   # shrink and move", 2).unwrap().unwrap();
+        let req = unsafe { amend.req.amend };
+        assert!(req.oid == 7 && req.qty == 3 && req.price == 45 && req.ob_id == 0);
+
+        // 0 is a NO price like any other
+        assert!(parse_line("A 5 0 0", 2).unwrap().is_some());
+
+        assert!(parse_line("", 2).unwrap().is_none());
+        assert!(parse_line("# just a comment", 2).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_refusals() {
+        for line in [
+            "X 1 2",
+            "A 5 -30",
+            "A 5 -30 0 I P extra",
+            "A five -30 0",
+            "A 5 -30 0 Q",
+            // checked like the engine would
+            "A 5 120 0",
+            "C 3 2",
+            "V 9",
+        ] {
+            assert!(parse_line(line, 2).is_err(), "'{}' parsed", line);
+        }
+
+        let err = parse_script("A 5 -30 0\n\nA 5 -30 5\n", 2).unwrap_err();
+        assert!(err.starts_with("line 3:"), "{}", err);
+        let reqs = parse_script("A 5 -30 0\n# nothing\nF 1\n", 2).unwrap();
+        assert!(reqs.iter().map(|(line, _)| *line).collect::<Vec<_>>() == vec![1, 3]);
+    }
+}
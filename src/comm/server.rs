@@ -0,0 +1,450 @@
+use crate::comm::manager::Router;
+use crate::comm::urcp::*;
+
+use std::io::{BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+
+// serves one URCP connection until it hangs up or sends something the engine can't route;
+// only ever takes down its own connection. `reader` and `writer` are two handles on the same socket
+pub fn serve<S: Read + Write + Send + 'static>(router: Arc<Router>, mut reader: S, mut writer: S) -> Result<()> {
+    let books = router.book_size() as u16;
+    handshake(&mut reader, &mut writer, books)?;
+
+    // books answer as soon as they're done; replies still go out in the order this
+    // connection sent the requests, each frame tagged with its request's corr
+    let (pending_tx, pending_rx) = channel::<(u32, Receiver<Vec<OBResponseWrapper>>)>();
+    let writer = thread::spawn(move || -> Result<()> {
+        let mut writer = BufWriter::new(writer);
+        loop {
+            // a pipelining client has more queued behind this one, only flush once it runs dry
+            let (corr, pending) = match pending_rx.try_recv() {
+                Ok(pending) => pending,
+                Err(TryRecvError::Empty) => {
+                    writer.flush()?;
+                    match pending_rx.recv() {
+                        Ok(pending) => pending,
+                        Err(_) => break,
+                    }
+                },
+                Err(TryRecvError::Disconnected) => break,
+            };
+            let resps = pending.recv().expect("engine worker died");
+            for resp in resps.iter() {
+                write_response(&mut writer, corr, &resp.typ, &resp.resp)?;
+            }
+        }
+        writer.flush()
+    });
+
+    let result = loop {
+        let frame = match read_frame(&mut reader) {
+            Ok(Ok(frame)) => frame,
+            Ok(Err(err)) => {
+                // nothing after a cut off frame can be trusted, answer and hang up
+                let _ = pending_tx.send((0, refuse(None, err)));
+                break Err(Error::from(err));
+            },
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        // the header gave the frame's length so one that doesn't decode still leaves the stream in step
+        let pending = match decode_request(&frame, books) {
+            Ok(request) if matches!(request.typ, OBReqType::HELLO) => {
+                println!("PROTOCOL: repeated HELLO");
+                refuse(Some(request.typ), ProtocolError::Unsupported)
+            },
+            Ok(request) => {
+                println!("{:?}", request);
+                router.dispatch(request)
+            },
+            Err(err) => {
+                println!("PROTOCOL: {}", err);
+                refuse(OBReqType::from_u8(frame.tag), err)
+            },
+        };
+        if pending_tx.send((frame.corr, pending)).is_err() {
+            // writer is gone, the client stopped reading
+            break Ok(());
+        }
+    };
+
+    // let whatever is already queued reach the client before the socket closes
+    drop(pending_tx);
+    let written = writer.join().unwrap();
+    result.and(written)
+}
+
+// every connection opens with HELLO. a peer that doesn't, or that can't talk to this
+// engine, gets a PROTOCOL frame saying why and is dropped before anything reaches a book.
+// both directions use handshake framing until the engine's HELLO is out
+fn handshake<S: Read + Write>(reader: &mut S, writer: &mut S, books: u16) -> Result<()> {
+    let frame = match read_hello_frame(reader)? {
+        Ok(frame) => frame,
+        Err(err) => return Err(Error::from(err)),
+    };
+    let err = if frame.tag == OBReqType::HELLO.to_u8() {
+        match decode_request(&frame, books) {
+            Ok(request) if unsafe { request.req.hello }.compatible() => {
+                let hello = HelloResponse::new(PROTOCOL_VERSION, FEATURES, books);
+                return writer.write_all(&encode_hello_response(&OBRespType::HELLO, &OBResponse { hello: hello }));
+            },
+            // includes a HELLO that doesn't parse, it came from another version
+            _ => ProtocolError::Incompatible,
+        }
+    } else {
+        ProtocolError::NoHello
+    };
+    println!("HANDSHAKE: {}", err);
+    writer.write_all(&encode_hello_response(&OBRespType::PROTOCOL, &OBResponse { protocol: ProtocolErrorResponse::new(err) }))?;
+    Err(Error::from(err))
+}
+
+// the reply to a frame that didn't decode, shaped like the reply the client is waiting for
+fn refuse(typ: Option<OBReqType>, err: ProtocolError) -> Receiver<Vec<OBResponseWrapper>> {
+    let mut resps = vec![OBResponseWrapper {
+        resp: OBResponse { protocol: ProtocolErrorResponse::new(err) },
+        typ: OBRespType::PROTOCOL,
+    }];
+    if typ.is_some_and(|typ| typ.replies_with_vec()) {
+        resps.push(OBResponseWrapper { resp: OBResponse { end: DelimResponse {} }, typ: OBRespType::DELIM });
+    }
+    let (tx, rx) = channel();
+    let _ = tx.send(resps);
+    rx
+}
+
+fn spawn_connection<S: Read + Write + Send + 'static>(router: &Arc<Router>, reader: Result<S>, writer: S) {
+    let reader = match reader {
+        Ok(reader) => reader,
+        Err(e) => {
+            println!("CONNECTION: {}", e);
+            return;
+        },
+    };
+    let router = Arc::clone(router);
+    thread::spawn(move || {
+        if let Err(e) = serve(router, reader, writer) {
+            println!("CONNECTION: {}", e);
+        }
+    });
+}
+
+// accept loops, one thread per connection; only returns if the listener itself fails
+
+pub fn accept_unix(router: Arc<Router>, listener: UnixListener) -> Result<()> {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => spawn_connection(&router, stream.try_clone(), stream),
+            Err(e) => println!("ACCEPT: {}", e),
+        }
+    }
+    Ok(())
+}
+
+pub fn accept_tcp(router: Arc<Router>, listener: TcpListener) -> Result<()> {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                // frames are tiny and every request waits on its reply
+                let _ = stream.set_nodelay(true);
+                spawn_connection(&router, stream.try_clone(), stream)
+            },
+            Err(e) => println!("ACCEPT: {}", e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::book::{OrderId, TimeInForce};
+    use crate::comm::manager::Manager;
+    use crate::comm::stream::{self, InnerStream, TCP_SCHEME};
+    use std::os::unix::net::UnixStream;
+
+    fn router() -> Arc<Router> {
+        Arc::new(Manager::new(100, 2).spawn(2))
+    }
+
+    // two clients on the same engine: orders from one show up in the other's resync
+    async fn exercise(addr: &str) {
+        let first = InnerStream::new(addr).await.unwrap();
+        let second = InnerStream::new(addr).await.unwrap();
+
+        let resps = first.add_order(AddRequest::new(5, -30, 1, TimeInForce::GTC, false, 0)).await.unwrap();
+        assert!(resps.len() == 1 && matches!(resps[0].typ, OBRespType::ADD));
+        let oid = unsafe { resps[0].resp.add.oid };
+
+        second.resync(1).await.unwrap();
+        assert!(second.get_price_levels()[1].get(&-30) == Some(&5));
+
+        // refused before they reach the connection, which keeps serving everyone else
+        let hello = first.send(OBReqType::HELLO, OBRequest { hello: HelloRequest::new(PROTOCOL_VERSION, FEATURES) });
+        assert!(hello.err().unwrap().kind() == std::io::ErrorKind::InvalidInput);
+        assert!(first.order_status(oid, 2).await.unwrap_err().kind() == std::io::ErrorKind::InvalidInput);
+
+        second.cancel_order(oid, 1).await.unwrap();
+        assert!(first.cancel_order(oid, 1).await.is_err());
+        first.resync(1).await.unwrap();
+        assert!(first.get_price_levels()[1].is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unix_loopback() {
+        let path = std::env::temp_dir().join(format!("fish-{}.socket", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let router = router();
+        thread::spawn(move || accept_unix(router, listener));
+
+        exercise(path.to_str().unwrap()).await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("{}{}", TCP_SCHEME, listener.local_addr().unwrap());
+        let router = router();
+        thread::spawn(move || accept_tcp(router, listener));
+
+        exercise(&addr).await;
+    }
+
+    fn connection() -> (UnixStream, thread::JoinHandle<Result<()>>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let router = router();
+        let reader = server.try_clone().unwrap();
+        (client, thread::spawn(move || serve(router, reader, server)))
+    }
+
+    fn refused_with(client: &mut UnixStream) -> ProtocolError {
+        let resp = read_response(client).unwrap();
+        assert!(matches!(resp.typ, OBRespType::PROTOCOL));
+        unsafe { resp.resp.protocol.err }
+    }
+
+    // refusals during the handshake come back in handshake framing
+    fn refused_hello(client: &mut UnixStream) -> ProtocolError {
+        let resp = read_hello_response(client).unwrap();
+        assert!(matches!(resp.typ, OBRespType::PROTOCOL));
+        unsafe { resp.resp.protocol.err }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (mut client, _served) = connection();
+        let hello = stream::handshake(&mut client).unwrap();
+        assert!(hello.version == PROTOCOL_VERSION && hello.features == FEATURES && hello.books == 2);
+
+        // only once per connection
+        write_request(&mut client, 1, &OBReqType::HELLO, &OBRequest { hello: HelloRequest::new(PROTOCOL_VERSION, FEATURES) }).unwrap();
+        assert!(refused_with(&mut client) == ProtocolError::Unsupported);
+    }
+
+    #[test]
+    fn test_handshake_refusals() {
+        // a request before HELLO
+        let (mut client, served) = connection();
+        write_request(&mut client, 1, &OBReqType::LEVELVIEW, &OBRequest { level_view: LevelViewRequest::new(0) }).unwrap();
+        assert!(refused_hello(&mut client) == ProtocolError::NoHello);
+        assert!(served.join().unwrap().is_err());
+
+        // another version, or a feature the engine doesn't have
+        for hello in [HelloRequest::new(PROTOCOL_VERSION + 1, FEATURES), HelloRequest::new(PROTOCOL_VERSION, 1 << 31)] {
+            let (mut client, served) = connection();
+            client.write_all(&encode_hello(&hello)).unwrap();
+            assert!(refused_hello(&mut client) == ProtocolError::Incompatible);
+            assert!(served.join().unwrap().is_err());
+        }
+
+        // a HELLO laid out some other way
+        let (mut client, served) = connection();
+        client.write_all(&[b'S', 2, 0, 1, 0]).unwrap();
+        assert!(refused_hello(&mut client) == ProtocolError::Incompatible);
+        assert!(served.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_v1_hello_is_refused() {
+        // a version 1 client's HELLO, byte for byte; it reads a 3 byte header back
+        let (mut client, served) = connection();
+        client.write_all(&[b'S', 6, 0, 1, 0, 0x0f, 0, 0, 0]).unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).unwrap();
+        assert!(reply == [b'P', 1, 0, b'V']);
+        assert!(served.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_bad_frames_keep_connection() {
+        let (mut client, served) = connection();
+        stream::handshake(&mut client).unwrap();
+
+        // a vector reply gets a PROTOCOL then DELIM, a single reply just the PROTOCOL
+        let bad_price = AddRequest::new(5, 100, 1, TimeInForce::GTC, false, 0);
+        write_request(&mut client, 1, &OBReqType::ADD, &OBRequest { add: bad_price }).unwrap();
+        let resps = read_response_vec(&mut client).unwrap();
+        assert!(resps.len() == 1 && matches!(resps[0].typ, OBRespType::PROTOCOL));
+        assert!(matches!(unsafe { resps[0].resp.protocol.err }, ProtocolError::PriceOutOfRange));
+
+        write_request(&mut client, 1, &OBReqType::LEVELVIEW, &OBRequest { level_view: LevelViewRequest::new(9) }).unwrap();
+        let resp = read_response(&mut client).unwrap();
+        assert!(matches!(unsafe { resp.resp.protocol.err }, ProtocolError::UnknownBook));
+
+        // the connection still works afterwards
+        let good = AddRequest::new(5, -30, 1, TimeInForce::GTC, false, 0);
+        write_request(&mut client, 1, &OBReqType::ADD, &OBRequest { add: good }).unwrap();
+        let resps = read_response_vec(&mut client).unwrap();
+        assert!(matches!(resps[0].typ, OBRespType::ADD));
+
+        // so does a tag it has never heard of, the header says how much to skip
+        client.write_all(&[b'z', 2, 0, 1, 0, 0, 0, 9, 9]).unwrap();
+        let resp = read_response(&mut client).unwrap();
+        assert!(matches!(unsafe { resp.resp.protocol.err }, ProtocolError::UnknownTag));
+        write_request(&mut client, 1, &OBReqType::LEVELVIEW, &OBRequest { level_view: LevelViewRequest::new(1) }).unwrap();
+        assert!(matches!(read_response(&mut client).unwrap().typ, OBRespType::LEVELVIEW));
+
+        // half a frame gets one last answer and the connection closes
+        client.write_all(&[b'A', 21, 0, 5, 0]).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let resp = read_response(&mut client).unwrap();
+        assert!(matches!(unsafe { resp.resp.protocol.err }, ProtocolError::Truncated));
+        assert!(served.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_orders_lists_the_book() {
+        let (mut client, _served) = connection();
+        stream::handshake(&mut client).unwrap();
+
+        let mut oids = Vec::new();
+        for price in [-30, 20] {
+            let add = AddRequest::new(5, price, 1, TimeInForce::GTC, false, 0);
+            write_request(&mut client, 1, &OBReqType::ADD, &OBRequest { add: add }).unwrap();
+            oids.push(unsafe { read_response_vec(&mut client).unwrap()[0].resp.add.oid });
+        }
+
+        write_request(&mut client, 2, &OBReqType::ORDERS, &OBRequest { orders: OrdersRequest::new(1) }).unwrap();
+        let listed: Vec<OrderId> = read_response_vec(&mut client).unwrap().iter()
+            .map(|resp| {
+                assert!(matches!(resp.typ, OBRespType::STATUS));
+                unsafe { resp.resp.status.oid }
+            })
+            .collect();
+        assert!(listed == oids);
+
+        // the other book has nothing
+        write_request(&mut client, 3, &OBReqType::ORDERS, &OBRequest { orders: OrdersRequest::new(0) }).unwrap();
+        assert!(read_response_vec(&mut client).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pipelined_replies_carry_corr() {
+        let (mut client, _served) = connection();
+        stream::handshake(&mut client).unwrap();
+
+        // everything goes out before anything is read, across both books
+        for corr in 1..=40u32 {
+            let ob_id = (corr % 2) as u16;
+            let add = AddRequest::new(1, -(corr as i8 % 50 + 1), ob_id, TimeInForce::GTC, false, 0);
+            write_request(&mut client, corr, &OBReqType::ADD, &OBRequest { add: add }).unwrap();
+        }
+
+        for corr in 1..=40u32 {
+            loop {
+                let frame = read_frame(&mut client).unwrap().unwrap();
+                assert!(frame.corr == corr);
+                if frame.tag == OBRespType::DELIM.to_u8() {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shared_stream() {
+        let path = std::env::temp_dir().join(format!("fish-shared-{}.socket", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let router = router();
+        thread::spawn(move || accept_unix(router, listener));
+
+        // many tasks on one stream, each waiting only on its own replies
+        let shared = Arc::new(InnerStream::new(path.to_str().unwrap()).await.unwrap());
+        let tasks: Vec<_> = (0..8).map(|i| {
+            let shared = Arc::clone(&shared);
+            tokio::spawn(async move {
+                for _ in 0..10 {
+                    let resps = shared.add_order(AddRequest::new(1, -(i + 1), 0, TimeInForce::GTC, false, 0)).await.unwrap();
+                    assert!(matches!(resps[0].typ, OBRespType::ADD));
+                }
+            })
+        }).collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // the deltas applied along the way add up to the engine's own view
+        let levels = shared.get_price_levels();
+        shared.resync_all().await.unwrap();
+        assert!(levels == shared.get_price_levels());
+        assert!(levels[0].values().sum::<u64>() == 80);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_stream_reconnects() {
+        let path = std::env::temp_dir().join(format!("fish-reconnect-{}.socket", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // the engine "restarts" after the first connection: the second one finds a fresh
+        // router that only has an order on book 1
+        let restarted = router();
+        restarted.dispatch(OBRequestWrapper {
+            req: OBRequest { add: AddRequest::new(4, -40, 1, TimeInForce::GTC, false, 0) },
+            typ: OBReqType::ADD,
+        }).recv().unwrap();
+        let (conns_tx, conns_rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let mut first = Some(router());
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                conns_tx.send(stream.try_clone().unwrap()).unwrap();
+                let router = first.take().unwrap_or_else(|| Arc::clone(&restarted));
+                spawn_connection(&router, stream.try_clone(), stream);
+            }
+        });
+
+        let stream = InnerStream::new(path.to_str().unwrap()).await.unwrap();
+        let mut link = stream.link();
+        let resps = stream.add_order(AddRequest::new(5, -30, 0, TimeInForce::GTC, false, 0)).await.unwrap();
+        let oid = unsafe { resps[0].resp.add.oid };
+        assert!(stream.get_price_levels()[0].get(&-30) == Some(&5));
+
+        conns_rx.recv().unwrap().shutdown(std::net::Shutdown::Both).unwrap();
+        let reconnected = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                link.changed().await.unwrap();
+                if let Some(epoch) = *link.borrow_and_update() {
+                    return epoch;
+                }
+            }
+        }).await.unwrap();
+        assert!(reconnected == 2 && stream.is_connected());
+
+        // levels come from the new engine, not what the old connection built up
+        let levels = stream.get_price_levels();
+        assert!(levels[0].is_empty() && levels[1].get(&-40) == Some(&4));
+        assert!(stream.order_status(oid, 0).await.is_err());
+        stream.add_order(AddRequest::new(1, -20, 0, TimeInForce::GTC, false, 0)).await.unwrap();
+        assert!(stream.get_price_levels()[0].get(&-20) == Some(&1));
+        let _ = std::fs::remove_file(&path);
+    }
+}
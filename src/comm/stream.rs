@@ -1,82 +1,467 @@
+use crate::comm::codec::{ClientCodec, HandshakeCodec};
 use crate::comm::urcp::*;
+use crate::book::book::OrderId;
 
-use std::collections::BTreeMap;
+use futures::{SinkExt, StreamExt};
+use std::collections::{BTreeMap, HashMap};
+use std::net::TcpStream;
 use std::os::unix::net::UnixStream;
-use std::io::Result;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_util::codec::Framed;
 
-pub struct InnerStream {
-    stream: UnixStream,
-    prices: [BTreeMap<i8, u64>; 2],
+// engine addresses are either "tcp://host:port" or a unix socket path
+pub const TCP_SCHEME: &str = "tcp://";
+
+pub fn connect(addr: &str) -> Result<Box<dyn Transport>> {
+    match addr.strip_prefix(TCP_SCHEME) {
+        Some(host) => {
+            let stream = TcpStream::connect(host)?;
+            // frames are tiny and every request waits on its reply
+            stream.set_nodelay(true)?;
+            Ok(Box::new(stream))
+        },
+        None => {
+            let stream = UnixStream::connect(addr)?;
+            stream.set_nonblocking(false)?;
+            stream.set_read_timeout(None)?;
+            stream.set_write_timeout(None)?;
+            Ok(Box::new(stream))
+        },
+    }
 }
 
-impl InnerStream {
-    pub fn new(addr: &'static str) -> Result<Self> {
-        let stream = UnixStream::connect(addr)?;
-        stream.set_nonblocking(false)?;
-        stream.set_read_timeout(None)?;
-        stream.set_write_timeout(None)?;
-        Ok(Self{
-            stream,
-            prices: [BTreeMap::new(), BTreeMap::new()],
-        })
+// opens a connection the way the engine expects; fails with the engine's reason if it
+// won't talk to this build
+pub fn handshake<S: Read + Write + ?Sized>(stream: &mut S) -> Result<HelloResponse> {
+    stream.write_all(&encode_hello(&hello_request()))?;
+    check_hello(read_hello_response(stream)?)
+}
+
+fn hello_request() -> HelloRequest {
+    HelloRequest::new(PROTOCOL_VERSION, FEATURES)
+}
+
+fn check_hello(resp: OBResponseWrapper) -> Result<HelloResponse> {
+    match resp.typ {
+        OBRespType::HELLO => {
+            let hello = unsafe { resp.resp.hello };
+            if hello.version != PROTOCOL_VERSION || hello.features & FEATURES != FEATURES {
+                return Err(Error::new(ErrorKind::ConnectionRefused, format!(
+                    "engine speaks URCP v{} with features {:#x}, this client needs v{} with {:#x}",
+                    hello.version, hello.features, PROTOCOL_VERSION, FEATURES,
+                )));
+            }
+            Ok(hello)
+        },
+        OBRespType::PROTOCOL => Err(Error::new(ErrorKind::ConnectionRefused, format!(
+            "engine refused URCP v{} handshake: {}", PROTOCOL_VERSION, unsafe { resp.resp.protocol.err },
+        ))),
+        _ => Err(Error::new(ErrorKind::InvalidData, "expected HELLO")),
     }
-    pub fn add_order(&mut self, qty: u64, price: i8, ob_id: u16) -> Result<Vec<OBResponseWrapper>> {
-        write_request(&mut self.stream, &OBReqType::ADD, &OBRequest{ add: AddRequest::new(qty, price, ob_id) })?;
-        let mut responses = read_response_vec(&mut self.stream)?;
-        responses.retain(|x| {
-            if matches!(x.typ, OBRespType::PRICE) {
-                unsafe { self.handle_price_level(x.resp.price, ob_id); }
-                false
+}
+
+// the engine couldn't read the request at all
+fn protocol_error(resp: &OBResponseWrapper) -> Error {
+    Error::new(ErrorKind::InvalidData, unsafe { resp.resp.protocol.err })
+}
+
+// a book refusal is a lone ERROR and a bad frame a lone PROTOCOL, for vector replies too
+fn check_refused(responses: &[OBResponseWrapper]) -> Result<()> {
+    if let Some(resp) = responses.first() {
+        match resp.typ {
+            OBRespType::ERROR => return Err(Error::new(ErrorKind::InvalidInput, unsafe { resp.resp.error.err })),
+            OBRespType::PROTOCOL => return Err(protocol_error(resp)),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// yes levels sit at |price|, no levels at 100 + price
+fn levels_from_view(view: &PriceViewResponse) -> BTreeMap<i8, u64> {
+    let mut levels = BTreeMap::new();
+    for (idx, qty) in view.prices.iter().enumerate() {
+        if *qty == 0 {
+            continue;
+        }
+        let price = if idx < 100 { -(idx as i8) } else { (idx - 100) as i8 };
+        levels.insert(price, *qty);
+    }
+    levels
+}
+
+fn apply_delta(levels: &mut BTreeMap<i8, u64>, plu: PriceLevelResponse) {
+    match levels.get_mut(&plu.price) {
+        None => {
+            levels.insert(plu.price, plu.delta as u64);
+        },
+        Some(entry) => {
+            if plu.delta < 0 {
+                *entry -= plu.delta.unsigned_abs()
             } else {
-                true
+                *entry += plu.delta.unsigned_abs()
             }
-        });
-        Ok(responses)
-    }
-    pub fn cancel_order(&mut self, oid: usize, ob_id: u16) -> Result<()> {
-        write_request(&mut self.stream, &OBReqType::CANCEL, &OBRequest{ cancel: CancelRequest::new(oid, ob_id) })?;
-        let price_level = read_response(&mut self.stream)?;
-        assert!(matches!(price_level.typ, OBRespType::PRICE));
-        unsafe {
-            self.handle_price_level(price_level.resp.price, ob_id);
         }
-        Ok(())
     }
-    pub fn reduce_order(&mut self, oid: usize, qty: u64, ob_id: u16) -> Result<()> {
-        write_request(&mut self.stream, &OBReqType::REDUCE, &OBRequest{ reduce: ReduceRequest::new(oid, qty, ob_id) })?;
-        let price_level = read_response(&mut self.stream)?;
-        assert!(matches!(price_level.typ, OBRespType::PRICE));
-        unsafe {
-            self.handle_price_level(price_level.resp.price, ob_id);
+}
+
+// a request on the wire, collecting its reply until the last frame is in
+struct InFlight {
+    typ: OBReqType,
+    ob_id: u16,
+    resps: Vec<OBResponseWrapper>,
+    reply: oneshot::Sender<Result<Vec<OBResponseWrapper>>>,
+}
+
+struct Command {
+    typ: OBReqType,
+    ob_id: u16,
+    req: OBRequest,
+    reply: oneshot::Sender<Result<Vec<OBResponseWrapper>>>,
+}
+
+// levels are shared with callers that only read them, never held across an await
+type Levels = Arc<Mutex<Vec<BTreeMap<i8, u64>>>>;
+
+// price deltas and level views are applied here in wire order, whoever is waiting on them
+fn take(in_flight: &mut HashMap<u32, InFlight>, prices: &Levels, corr: u32, resp: OBResponseWrapper) {
+    let pending = match in_flight.get_mut(&corr) {
+        Some(pending) => pending,
+        None => {
+            println!("URCP: reply for unknown request {}", corr);
+            return;
+        },
+    };
+
+    // a single frame reply is done as soon as it arrives, a vector one at its DELIM
+    let done = match resp.typ {
+        OBRespType::DELIM => true,
+        OBRespType::PRICE => {
+            let mut prices = prices.lock().unwrap();
+            apply_delta(&mut prices[pending.ob_id as usize], unsafe { resp.resp.price });
+            !pending.typ.replies_with_vec()
+        },
+        _ => {
+            pending.resps.push(resp);
+            !pending.typ.replies_with_vec()
+        },
+    };
+    if !done {
+        return;
+    }
+
+    let pending = in_flight.remove(&corr).unwrap();
+    let result = check_refused(&pending.resps).and_then(|_| {
+        let mut prices = prices.lock().unwrap();
+        match pending.typ {
+            OBReqType::LEVELVIEW => match pending.resps.first() {
+                Some(resp) if matches!(resp.typ, OBRespType::LEVELVIEW) => {
+                    prices[pending.ob_id as usize] = levels_from_view(unsafe { &resp.resp.view });
+                },
+                _ => return Err(Error::new(ErrorKind::InvalidData, "expected level view")),
+            },
+            OBReqType::FLUSH => prices[pending.ob_id as usize].clear(),
+            _ => (),
         }
         Ok(())
+    });
+    let _ = pending.reply.send(result.map(|_| pending.resps));
+}
+
+// any socket the engine can be reached over
+trait Socket: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> Socket for S {}
+
+type Connection = Framed<Box<dyn Socket>, ClientCodec>;
+
+// first retry comes quickly, an engine that stays down is retried every MAX_BACKOFF
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+// connects, handshakes and pulls every book's levels, so a fresh connection starts from the
+// engine's own depth. `books` is what the last connection had; the engine can't change it
+async fn open(addr: &str, books: Option<u16>) -> Result<(Connection, Vec<BTreeMap<i8, u64>>)> {
+    let socket: Box<dyn Socket> = match addr.strip_prefix(TCP_SCHEME) {
+        Some(host) => {
+            let stream = tokio::net::TcpStream::connect(host).await?;
+            // frames are tiny and every request waits on its reply
+            stream.set_nodelay(true)?;
+            Box::new(stream)
+        },
+        None => Box::new(tokio::net::UnixStream::connect(addr).await?),
+    };
+    let mut handshake = Framed::new(socket, HandshakeCodec);
+
+    handshake.send(hello_request()).await?;
+    let hello = match handshake.next().await {
+        Some(resp) => check_hello(resp?)?,
+        None => return Err(Error::new(ErrorKind::UnexpectedEof, "engine hung up during the handshake")),
+    };
+    // anything already read past the HELLO stays buffered for the normal framing
+    let mut framed = handshake.map_codec(|_| ClientCodec);
+    match books {
+        Some(books) if books != hello.books => return Err(Error::new(ErrorKind::ConnectionRefused, format!(
+            "engine came back with {} books, it had {}", hello.books, books,
+        ))),
+        _ => (),
     }
-    pub fn flush_book(&mut self, ob_id: u16) -> Result<()> {
-        self.prices[0].clear();
-        self.prices[1].clear();
-        write_request(&mut self.stream, &OBReqType::FLUSH, &OBRequest { flush: FlushRequest::new(ob_id) })?;
-        let delim_resp = read_response(&mut self.stream)?; 
-        assert!(matches!(delim_resp.typ, OBRespType::DELIM));
-        Ok(())
+
+    // book i's view goes out as corr i + 1; nothing else is in flight yet
+    for ob_id in 0..hello.books {
+        framed.feed((ob_id as u32 + 1, OBReqType::LEVELVIEW, OBRequest { level_view: LevelViewRequest::new(ob_id) })).await?;
+    }
+    framed.flush().await?;
+    let mut levels = vec![BTreeMap::new(); hello.books as usize];
+    for _ in 0..hello.books {
+        let (corr, resp) = match framed.next().await {
+            Some(frame) => frame?,
+            None => return Err(Error::new(ErrorKind::UnexpectedEof, "engine hung up during the resync")),
+        };
+        check_refused(std::slice::from_ref(&resp))?;
+        match levels.get_mut((corr as usize).wrapping_sub(1)) {
+            Some(book) if matches!(resp.typ, OBRespType::LEVELVIEW) => *book = levels_from_view(unsafe { &resp.resp.view }),
+            _ => return Err(Error::new(ErrorKind::InvalidData, "expected level view")),
+        }
     }
-    pub fn handle_price_level(&mut self, plu: PriceLevelResponse, ob_id: u16) {
-        match self.prices[ob_id as usize].get_mut(&plu.price) {
-            None => {
-                self.prices[ob_id as usize].insert(plu.price, plu.delta as u64);
+
+    Ok((framed, levels))
+}
+
+// how a connection ended
+enum Closed {
+    // every handle on the stream is gone, nothing left to serve
+    Done,
+    Dropped(String),
+}
+
+// owns one connection: writes what callers send, hands replies back by corr
+async fn run(
+    mut framed: Connection,
+    commands: &mut mpsc::UnboundedReceiver<Command>,
+    prices: &Levels,
+) -> Closed {
+    let mut in_flight: HashMap<u32, InFlight> = HashMap::new();
+    // 0 belongs to the handshake
+    let mut next_corr: u32 = 1;
+
+    let reason = loop {
+        tokio::select! {
+            command = commands.recv() => {
+                let mut command = match command {
+                    Some(command) => command,
+                    None => return Closed::Done,
+                };
+                // whatever else is queued goes out in the same write
+                let written: Result<()> = async {
+                    loop {
+                        let corr = next_corr;
+                        next_corr = next_corr.checked_add(1).unwrap_or(1);
+                        in_flight.insert(corr, InFlight {
+                            typ: command.typ,
+                            ob_id: command.ob_id,
+                            resps: Vec::new(),
+                            reply: command.reply,
+                        });
+                        framed.feed((corr, command.typ, command.req)).await?;
+                        command = match commands.try_recv() {
+                            Ok(command) => command,
+                            Err(_) => break,
+                        };
+                    }
+                    framed.flush().await
+                }.await;
+                if let Err(e) = written {
+                    break e.to_string();
+                }
             },
-            Some(entry) => {
-                if plu.delta < 0 {
-                    *entry -= plu.delta.abs() as u64
-                } else {
-                    *entry += plu.delta.abs() as u64
+            frame = framed.next() => match frame {
+                Some(Ok((corr, resp))) => take(&mut in_flight, prices, corr, resp),
+                Some(Err(e)) => break e.to_string(),
+                None => break String::from("engine hung up"),
+            },
+        }
+    };
+
+    // the engine may or may not have applied these, their callers have to find out
+    let reason = format!("engine connection closed: {}", reason);
+    for (_, pending) in in_flight.drain() {
+        let _ = pending.reply.send(Err(Error::new(ErrorKind::ConnectionAborted, reason.clone())));
+    }
+    Closed::Dropped(reason)
+}
+
+// keeps the stream connected: runs a connection until it drops, then reconnects with
+// backoff. requests made while there's no connection fail straight away
+async fn supervise(
+    addr: String,
+    mut framed: Connection,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    prices: Levels,
+    link: watch::Sender<Option<u64>>,
+) {
+    let books = prices.lock().unwrap().len() as u16;
+    let mut epoch = 1;
+
+    loop {
+        match run(framed, &mut commands, &prices).await {
+            Closed::Done => return,
+            Closed::Dropped(reason) => println!("URCP: {}, reconnecting", reason),
+        }
+        link.send_replace(None);
+
+        let mut backoff = MIN_BACKOFF;
+        framed = loop {
+            let retry = tokio::time::sleep(backoff);
+            tokio::pin!(retry);
+            loop {
+                tokio::select! {
+                    _ = &mut retry => break,
+                    command = commands.recv() => match command {
+                        Some(command) => {
+                            let _ = command.reply.send(Err(Error::new(ErrorKind::NotConnected, "engine unavailable")));
+                        },
+                        None => return,
+                    },
                 }
             }
-        }
+
+            match open(&addr, Some(books)).await {
+                Ok((framed, levels)) => {
+                    *prices.lock().unwrap() = levels;
+                    break framed;
+                },
+                Err(e) => {
+                    println!("URCP: reconnect to {} failed: {}", addr, e);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                },
+            }
+        };
+
+        epoch += 1;
+        link.send_replace(Some(epoch));
     }
-    pub fn get_price_levels(&self) -> Vec<BTreeMap<i8, u64>> {
-        self.prices.to_vec()
+}
+
+// the engine's answer to one request
+pub struct Reply {
+    rx: oneshot::Receiver<Result<Vec<OBResponseWrapper>>>,
+}
+
+impl Reply {
+    // resolves once the whole reply is in. refusals come back as errors, price deltas are
+    // already applied to the levels and left out
+    pub async fn wait(self: Self) -> Result<Vec<OBResponseWrapper>> {
+        match self.rx.await {
+            Ok(result) => result,
+            Err(_) => Err(Error::new(ErrorKind::ConnectionAborted, "engine connection closed")),
+        }
     }
 }
 
+// One engine connection, shared by every caller
+//
+// a background task owns the socket. callers hand it requests and await their own
+// replies, so any number of them can be in flight without anyone blocking a thread.
+// if the engine goes away the task reconnects on its own and rebuilds the levels
+pub struct InnerStream {
+    commands: mpsc::UnboundedSender<Command>,
+    prices: Levels,
+    link: watch::Receiver<Option<u64>>,
+}
+
+impl InnerStream {
+    // needs to be called inside a tokio runtime, the socket's task is spawned on it. the
+    // first connection has to work; it's only retried once it has been up
+    pub async fn new(addr: &str) -> Result<Self> {
+        let (framed, levels) = open(addr, None).await?;
 
+        let prices = Arc::new(Mutex::new(levels));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (link_tx, link_rx) = watch::channel(Some(1));
+        tokio::spawn(supervise(addr.to_string(), framed, rx, Arc::clone(&prices), link_tx));
 
+        Ok(Self{
+            commands: tx,
+            prices: prices,
+            link: link_rx,
+        })
+    }
+    // Some(n) while the n-th connection is up, None while reconnecting. requests sent on
+    // an earlier connection may or may not have reached the engine
+    pub fn link(&self) -> watch::Receiver<Option<u64>> {
+        self.link.clone()
+    }
+    pub fn is_connected(&self) -> bool {
+        self.link.borrow().is_some()
+    }
+    // the n in Some(n) from link(), as of now
+    pub fn epoch(&self) -> Option<u64> {
+        *self.link.borrow()
+    }
+    pub fn books(&self) -> u16 {
+        self.prices.lock().unwrap().len() as u16
+    }
+    // queues a request for the wire and returns without waiting for the engine. only
+    // requests for one of the engine's books go through here, the handshake is done already
+    pub fn send(&self, typ: OBReqType, req: OBRequest) -> Result<Reply> {
+        let ob_id = match (OBRequestWrapper { req: req, typ: typ }).ob_id() {
+            Some(ob_id) if ob_id < self.books() => ob_id,
+            Some(ob_id) => return Err(Error::new(ErrorKind::InvalidInput, format!("no book {}", ob_id))),
+            None => return Err(Error::new(ErrorKind::InvalidInput, "request isn't for a book")),
+        };
+        let (tx, rx) = oneshot::channel();
+        match self.commands.send(Command { typ: typ, ob_id: ob_id, req: req, reply: tx }) {
+            Ok(()) => Ok(Reply { rx: rx }),
+            Err(_) => Err(Error::new(ErrorKind::NotConnected, "engine connection closed")),
+        }
+    }
+    pub async fn add_order(&self, req: AddRequest) -> Result<Vec<OBResponseWrapper>> {
+        self.send(OBReqType::ADD, OBRequest { add: req })?.wait().await
+    }
+    pub async fn market_order(&self, qty: u64, limit: i8, ob_id: u16) -> Result<Vec<OBResponseWrapper>> {
+        self.send(OBReqType::MARKET, OBRequest { market: MarketRequest::new(qty, limit, ob_id) })?.wait().await
+    }
+    pub async fn tick(&self, now: u64, ob_id: u16) -> Result<Vec<OBResponseWrapper>> {
+        self.send(OBReqType::TICK, OBRequest { tick: TickRequest::new(now, ob_id) })?.wait().await
+    }
+    pub async fn amend_order(&self, oid: OrderId, qty: u64, price: i8, ob_id: u16) -> Result<Vec<OBResponseWrapper>> {
+        self.send(OBReqType::AMEND, OBRequest { amend: AmendRequest::new(oid, qty, price, ob_id) })?.wait().await
+    }
+    pub async fn cancel_order(&self, oid: OrderId, ob_id: u16) -> Result<()> {
+        self.send(OBReqType::CANCEL, OBRequest { cancel: CancelRequest::new(oid, ob_id) })?.wait().await.map(|_| ())
+    }
+    pub async fn reduce_order(&self, oid: OrderId, qty: u64, ob_id: u16) -> Result<()> {
+        self.send(OBReqType::REDUCE, OBRequest { reduce: ReduceRequest::new(oid, qty, ob_id) })?.wait().await.map(|_| ())
+    }
+    pub async fn order_status(&self, oid: OrderId, ob_id: u16) -> Result<StatusResponse> {
+        let resps = self.send(OBReqType::STATUS, OBRequest { status: StatusRequest::new(oid, ob_id) })?.wait().await?;
+        match resps.first() {
+            Some(resp) if matches!(resp.typ, OBRespType::STATUS) => Ok(unsafe { resp.resp.status }),
+            _ => Err(Error::new(ErrorKind::InvalidData, "expected status")),
+        }
+    }
+    pub async fn flush_book(&self, ob_id: u16) -> Result<()> {
+        self.send(OBReqType::FLUSH, OBRequest { flush: FlushRequest::new(ob_id) })?.wait().await.map(|_| ())
+    }
+    // replace the delta-built levels for a book with the engine's own depth
+    pub async fn resync(&self, ob_id: u16) -> Result<()> {
+        self.send(OBReqType::LEVELVIEW, OBRequest { level_view: LevelViewRequest::new(ob_id) })?.wait().await.map(|_| ())
+    }
+    // every book's view is requested before any comes back
+    pub async fn resync_all(&self) -> Result<()> {
+        let replies = (0..self.books())
+            .map(|ob_id| self.send(OBReqType::LEVELVIEW, OBRequest { level_view: LevelViewRequest::new(ob_id) }))
+            .collect::<Result<Vec<Reply>>>()?;
+        for reply in replies {
+            reply.wait().await?;
+        }
+        Ok(())
+    }
+    pub fn get_price_levels(&self) -> Vec<BTreeMap<i8, u64>> {
+        self.prices.lock().unwrap().clone()
+    }
+}
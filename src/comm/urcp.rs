@@ -1,22 +1,323 @@
+use crate::book::book::{BookError, OrderId, OrderStatus, TimeInForce};
 use serde::Serialize;
 use derive_more::Constructor;
 use std::io::prelude::*;
 use std::io::Result;
-use std::mem;
+use std::mem::ManuallyDrop;
+use std::net::{Shutdown, TcpStream};
 use std::os::unix::net::UnixStream;
 
-unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
-    let ret = ::core::slice::from_raw_parts((p as *const T) as *const u8, mem::size_of::<T>());
-    debug_assert_eq!(ret.len(), mem::size_of::<T>());
-    ret
+// anything URCP can run over, unix sockets locally and tcp across hosts
+pub trait Transport: Read + Write + Send {
+    // another handle on the same connection, so one thread can read while others write
+    fn try_clone_transport(&self) -> Result<Box<dyn Transport>>;
+    // closes both directions for every handle
+    fn shutdown_transport(&self) -> Result<()>;
 }
 
-unsafe fn u8_slice_to_struct<T: Copy>(s: &[u8]) -> T {
-    assert_eq!(s.len(), mem::size_of::<T>());
+impl Transport for UnixStream {
+    fn try_clone_transport(&self) -> Result<Box<dyn Transport>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+    fn shutdown_transport(&self) -> Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
+
+impl Transport for TcpStream {
+    fn try_clone_transport(&self) -> Result<Box<dyn Transport>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+    fn shutdown_transport(&self) -> Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
+
+// what's wrong with a frame that didn't decode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ProtocolError {
+    // tag byte isn't a known request or response
+    UnknownTag = b'T',
+    // connection closed partway through a frame
+    Truncated = b'.',
+    // an enum or bool field holds a byte it can't take
+    BadField = b'F',
+    // price or limit outside -99..=99
+    PriceOutOfRange = b'P',
+    // ob_id past the books the engine runs
+    UnknownBook = b'B',
+    // known request the engine doesn't serve
+    Unsupported = b'S',
+    // payload is shorter or longer than its tag calls for
+    BadLength = b'L',
+    // first frame on the connection wasn't HELLO
+    NoHello = b'H',
+    // peer speaks another protocol version or wants features the engine doesn't have
+    Incompatible = b'V',
+}
+
+impl ProtocolError {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            b'T' => Some(ProtocolError::UnknownTag),
+            b'.' => Some(ProtocolError::Truncated),
+            b'F' => Some(ProtocolError::BadField),
+            b'P' => Some(ProtocolError::PriceOutOfRange),
+            b'B' => Some(ProtocolError::UnknownBook),
+            b'S' => Some(ProtocolError::Unsupported),
+            b'L' => Some(ProtocolError::BadLength),
+            b'H' => Some(ProtocolError::NoHello),
+            b'V' => Some(ProtocolError::Incompatible),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::UnknownTag => f.write_str("unknown frame tag"),
+            ProtocolError::Truncated => f.write_str("truncated frame"),
+            ProtocolError::BadField => f.write_str("invalid field value"),
+            ProtocolError::PriceOutOfRange => f.write_str("price out of range"),
+            ProtocolError::UnknownBook => f.write_str("unknown book"),
+            ProtocolError::Unsupported => f.write_str("unsupported request"),
+            ProtocolError::BadLength => f.write_str("payload length doesn't match its tag"),
+            ProtocolError::NoHello => f.write_str("connection didn't open with HELLO"),
+            ProtocolError::Incompatible => f.write_str("incompatible protocol version or features"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for std::io::Error {
+    fn from(err: ProtocolError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
+// URCP wire format
+//
+// every message is [tag u8][len u16][corr u32][payload; len]. integers are little endian
+// and fixed width (oids are u64), bools are 0 or 1, enums are their tag byte and f64 is its
+// IEEE bits. payload fields go in struct declaration order with no padding. the header says
+// how long every frame is, so one that doesn't decode is skipped without losing the stream's place
+//
+// corr is picked by the client and every frame of the reply carries it back, so a client can
+// have many requests in flight and match the replies as they come in. the engine happens to
+// answer a connection in request order, clients shouldn't count on it
+//
+// the handshake is the exception: HELLO and the engine's answer to it keep version 1's
+// [tag u8][len u16][payload; len] with no corr, so a peer on any version can frame them
+
+pub const HEADER: usize = 7;
+pub const HELLO_HEADER: usize = 3;
+
+// bumped whenever a frame's layout or meaning changes; peers have to agree exactly
+pub const PROTOCOL_VERSION: u16 = 2;
+
+// optional parts of the protocol, a peer can only use what the other side offers
+pub const FEATURE_TIME_IN_FORCE: u32 = 1 << 0;
+pub const FEATURE_AMEND: u32 = 1 << 1;
+pub const FEATURE_MARKET: u32 = 1 << 2;
+// GTT orders and TICK
+pub const FEATURE_EXPIRY: u32 = 1 << 3;
+pub const FEATURE_ORDERS: u32 = 1 << 4;
+
+// everything this build speaks
+pub const FEATURES: u32 = FEATURE_TIME_IN_FORCE | FEATURE_AMEND | FEATURE_MARKET | FEATURE_EXPIRY | FEATURE_ORDERS;
+
+pub struct Frame {
+    pub tag: u8,
+    pub corr: u32,
+    pub payload: Vec<u8>,
+}
 
-    // Create an unaligned reference to the slice and read it as a T
-    let ptr = s.as_ptr() as *const T;
-    ptr.read_unaligned()
+impl Frame {
+    // the whole frame's length, from a buffer that holds at least its tag and len
+    pub fn wire_len(buf: &[u8]) -> usize {
+        HEADER + u16::from_le_bytes([buf[1], buf[2]]) as usize
+    }
+    // `buf` is exactly one frame, header included
+    pub fn from_wire(buf: &[u8]) -> Self {
+        Frame {
+            tag: buf[0],
+            corr: u32::from_le_bytes([buf[3], buf[4], buf[5], buf[6]]),
+            payload: buf[HEADER..].to_vec(),
+        }
+    }
+    // the same for a handshake frame, which has no corr of its own
+    pub fn hello_wire_len(buf: &[u8]) -> usize {
+        HELLO_HEADER + u16::from_le_bytes([buf[1], buf[2]]) as usize
+    }
+    pub fn from_hello_wire(buf: &[u8]) -> Self {
+        Frame {
+            tag: buf[0],
+            corr: 0,
+            payload: buf[HELLO_HEADER..].to_vec(),
+        }
+    }
+}
+
+// one fixed width value in a payload
+trait Field: Sized {
+    fn put(&self, buf: &mut Vec<u8>);
+    fn take(buf: &mut &[u8]) -> std::result::Result<Self, ProtocolError>;
+}
+
+fn take_bytes<const N: usize>(buf: &mut &[u8]) -> std::result::Result<[u8; N], ProtocolError> {
+    let (head, rest) = buf.split_first_chunk::<N>().ok_or(ProtocolError::BadLength)?;
+    *buf = rest;
+    Ok(*head)
+}
+
+macro_rules! int_field {
+    ($($t:ty),*) => {$(
+        impl Field for $t {
+            fn put(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_le_bytes());
+            }
+            fn take(buf: &mut &[u8]) -> std::result::Result<Self, ProtocolError> {
+                Ok(<$t>::from_le_bytes(take_bytes(buf)?))
+            }
+        }
+    )*};
+}
+
+int_field!(u8, i8, u16, u32, u64, i64);
+
+// enums that travel as their tag byte
+macro_rules! tag_field {
+    ($($t:ty),*) => {$(
+        impl Field for $t {
+            fn put(&self, buf: &mut Vec<u8>) {
+                buf.push(*self as u8);
+            }
+            fn take(buf: &mut &[u8]) -> std::result::Result<Self, ProtocolError> {
+                <$t>::from_u8(u8::take(buf)?).ok_or(ProtocolError::BadField)
+            }
+        }
+    )*};
+}
+
+tag_field!(TimeInForce, OrderStatus, BookError, ProtocolError);
+
+impl Field for bool {
+    fn put(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+    fn take(buf: &mut &[u8]) -> std::result::Result<Self, ProtocolError> {
+        match u8::take(buf)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(ProtocolError::BadField),
+        }
+    }
+}
+
+impl Field for f64 {
+    fn put(&self, buf: &mut Vec<u8>) {
+        self.to_bits().put(buf);
+    }
+    fn take(buf: &mut &[u8]) -> std::result::Result<Self, ProtocolError> {
+        Ok(f64::from_bits(u64::take(buf)?))
+    }
+}
+
+impl<const N: usize> Field for [u64; N] {
+    fn put(&self, buf: &mut Vec<u8>) {
+        for v in self.iter() {
+            v.put(buf);
+        }
+    }
+    fn take(buf: &mut &[u8]) -> std::result::Result<Self, ProtocolError> {
+        let mut ret = [0u64; N];
+        for v in ret.iter_mut() {
+            *v = u64::take(buf)?;
+        }
+        Ok(ret)
+    }
+}
+
+// a whole payload; decoding wants every byte used
+trait Message: Sized {
+    fn encode(&self, buf: &mut Vec<u8>);
+    fn decode(payload: &[u8]) -> std::result::Result<Self, ProtocolError>;
+}
+
+// fields are listed in declaration order; leaving one out doesn't compile
+macro_rules! message {
+    ($($t:ident { $($f:ident),+ })*) => {$(
+        impl Message for $t {
+            fn encode(&self, buf: &mut Vec<u8>) {
+                $(self.$f.put(buf);)+
+            }
+            fn decode(mut payload: &[u8]) -> std::result::Result<Self, ProtocolError> {
+                let msg = $t { $($f: Field::take(&mut payload)?,)+ };
+                if !payload.is_empty() {
+                    return Err(ProtocolError::BadLength);
+                }
+                Ok(msg)
+            }
+        }
+    )*};
+}
+
+fn frame<M: Message>(tag: u8, corr: u32, msg: &M) -> Vec<u8> {
+    let mut buf = vec![tag, 0, 0];
+    corr.put(&mut buf);
+    msg.encode(&mut buf);
+    let len = (buf.len() - HEADER) as u16;
+    buf[1..3].copy_from_slice(&len.to_le_bytes());
+    buf
+}
+
+// a frame in handshake framing, the normal one without its corr
+fn hello_frame(mut buf: Vec<u8>) -> Vec<u8> {
+    buf.drain(HELLO_HEADER..HEADER);
+    buf
+}
+
+// the outer error is the connection, the inner one a frame that didn't make it.
+// a clean close between frames is UnexpectedEof, one partway through is Truncated
+pub fn read_frame<S: Read + ?Sized>(stream: &mut S) -> Result<std::result::Result<Frame, ProtocolError>> {
+    read_framed(stream, HEADER, Frame::wire_len, Frame::from_wire)
+}
+
+// the first frame on a connection, in handshake framing; it comes back with corr 0
+pub fn read_hello_frame<S: Read + ?Sized>(stream: &mut S) -> Result<std::result::Result<Frame, ProtocolError>> {
+    read_framed(stream, HELLO_HEADER, Frame::hello_wire_len, Frame::from_hello_wire)
+}
+
+fn read_framed<S: Read + ?Sized>(
+    stream: &mut S,
+    header: usize,
+    wire_len: fn(&[u8]) -> usize,
+    from_wire: fn(&[u8]) -> Frame,
+) -> Result<std::result::Result<Frame, ProtocolError>> {
+    let mut buf = vec![0u8; header];
+    stream.read_exact(&mut buf[..1])?;
+    let mut read_rest = || -> Result<()> {
+        stream.read_exact(&mut buf[1..])?;
+        buf.resize(wire_len(&buf), 0);
+        stream.read_exact(&mut buf[header..])
+    };
+    match read_rest() {
+        Ok(()) => Ok(Ok(from_wire(&buf))),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(Err(ProtocolError::Truncated)),
+        Err(e) => Err(e),
+    }
+}
+
+// the same prices the book has levels for; negative is YES, 0 and up is NO
+fn check_price(price: i8) -> std::result::Result<(), ProtocolError> {
+    if (-99..=99).contains(&price) {
+        Ok(())
+    } else {
+        Err(ProtocolError::PriceOutOfRange)
+    }
 }
 
 // -------
@@ -28,8 +329,14 @@ pub enum OBReqType {
     CANCEL = b'C',
     REDUCE = b'R',
     FLUSH = b'F',
-    START = b'S',
+    // first frame on every connection, takes the slot START used to have
+    HELLO = b'S',
     LEVELVIEW = b'V',
+    STATUS = b'Q',
+    MARKET = b'M',
+    TICK = b'T',
+    AMEND = b'U',
+    ORDERS = b'O',
     UNREACHABLE = b'-', // if i don't have it infinite loop bitches at me
 }
 
@@ -37,8 +344,30 @@ impl OBReqType {
     pub fn to_u8(&self) -> u8 {
         *self as u8
     }
-    pub fn from_u8(v: u8) -> Self {
-        unsafe { mem::transmute(v) }
+    // None for anything that isn't a request tag
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            b'A' => Some(OBReqType::ADD),
+            b'C' => Some(OBReqType::CANCEL),
+            b'R' => Some(OBReqType::REDUCE),
+            b'F' => Some(OBReqType::FLUSH),
+            b'S' => Some(OBReqType::HELLO),
+            b'V' => Some(OBReqType::LEVELVIEW),
+            b'Q' => Some(OBReqType::STATUS),
+            b'M' => Some(OBReqType::MARKET),
+            b'T' => Some(OBReqType::TICK),
+            b'U' => Some(OBReqType::AMEND),
+            b'O' => Some(OBReqType::ORDERS),
+            _ => None,
+        }
+    }
+    // the engine answers these with a DELIM terminated vector rather than a single frame
+    pub fn replies_with_vec(&self) -> bool {
+        matches!(self, OBReqType::ADD | OBReqType::MARKET | OBReqType::AMEND | OBReqType::TICK | OBReqType::ORDERS)
+    }
+    // anything but a read; only these have to be journaled
+    pub fn changes_book(&self) -> bool {
+        !matches!(self, OBReqType::LEVELVIEW | OBReqType::STATUS | OBReqType::ORDERS | OBReqType::HELLO | OBReqType::UNREACHABLE)
     }
 }
 
@@ -47,6 +376,27 @@ pub struct OBRequestWrapper {
     pub typ: OBReqType,
 }
 
+impl OBRequestWrapper {
+    // the book a request is run against, None for requests the engine doesn't route
+    pub fn ob_id(&self) -> Option<u16> {
+        unsafe {
+            match self.typ {
+                OBReqType::ADD => Some(self.req.add.ob_id),
+                OBReqType::CANCEL => Some(self.req.cancel.ob_id),
+                OBReqType::REDUCE => Some(self.req.reduce.ob_id),
+                OBReqType::FLUSH => Some(self.req.flush.ob_id),
+                OBReqType::LEVELVIEW => Some(self.req.level_view.ob_id),
+                OBReqType::STATUS => Some(self.req.status.ob_id),
+                OBReqType::MARKET => Some(self.req.market.ob_id),
+                OBReqType::TICK => Some(self.req.tick.ob_id),
+                OBReqType::AMEND => Some(self.req.amend.ob_id),
+                OBReqType::ORDERS => Some(self.req.orders.ob_id),
+                _ => None,
+            }
+        }
+    }
+}
+
 impl std::fmt::Debug for OBRequestWrapper {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.typ {
@@ -54,8 +404,13 @@ impl std::fmt::Debug for OBRequestWrapper {
             OBReqType::CANCEL => unsafe { self.req.cancel.fmt(f) },
             OBReqType::REDUCE => unsafe { self.req.reduce.fmt(f) },
             OBReqType::FLUSH => unsafe { self.req.flush.fmt(f) },
-            OBReqType::START => unsafe { self.req.start.fmt(f) },
-            OBReqType::LEVELVIEW => unsafe { self.req.start.fmt(f) },
+            OBReqType::HELLO => unsafe { self.req.hello.fmt(f) },
+            OBReqType::LEVELVIEW => unsafe { self.req.level_view.fmt(f) },
+            OBReqType::STATUS => unsafe { self.req.status.fmt(f) },
+            OBReqType::MARKET => unsafe { self.req.market.fmt(f) },
+            OBReqType::TICK => unsafe { self.req.tick.fmt(f) },
+            OBReqType::AMEND => unsafe { self.req.amend.fmt(f) },
+            OBReqType::ORDERS => unsafe { self.req.orders.fmt(f) },
             _ => f.write_str("unreachable"),
         }
     }
@@ -68,8 +423,13 @@ pub union OBRequest {
     pub cancel: CancelRequest,
     pub reduce: ReduceRequest,
     pub flush: FlushRequest,
-    pub start: StartRequest,
+    pub hello: HelloRequest,
     pub level_view: LevelViewRequest,
+    pub status: StatusRequest,
+    pub market: MarketRequest,
+    pub tick: TickRequest,
+    pub amend: AmendRequest,
+    pub orders: OrdersRequest,
 }
 
 #[derive(Debug, Constructor, Clone, Copy)]
@@ -77,17 +437,44 @@ pub struct AddRequest {
     pub qty: u64,
     pub price: i8,
     pub ob_id: u16,
+    pub tif: TimeInForce,
+    pub post_only: bool,
+    pub expires_at: u64,
+}
+
+// `limit` is the worst price the taker accepts, signed like AddRequest.price
+#[derive(Debug, Constructor, Clone, Copy)]
+pub struct MarketRequest {
+    pub qty: u64,
+    pub limit: i8,
+    pub ob_id: u16,
+}
+
+// moves the book clock forward; `now` is whatever unit GTT expiries were given in
+#[derive(Debug, Constructor, Clone, Copy)]
+pub struct TickRequest {
+    pub now: u64,
+    pub ob_id: u16,
+}
+
+// qty is the new total size, price is signed like AddRequest.price
+#[derive(Debug, Constructor, Clone, Copy)]
+pub struct AmendRequest {
+    pub oid: OrderId,
+    pub qty: u64,
+    pub price: i8,
+    pub ob_id: u16,
 }
 
 #[derive(Debug, Constructor, Clone, Copy)]
 pub struct CancelRequest {
-    pub oid: usize,
+    pub oid: OrderId,
     pub ob_id: u16,
 }
 
 #[derive(Debug, Constructor, Clone, Copy)]
 pub struct ReduceRequest {
-    pub oid: usize,
+    pub oid: OrderId,
     pub qty: u64,
     pub ob_id: u16,
 }
@@ -97,9 +484,18 @@ pub struct FlushRequest {
     pub ob_id: u16,
 }
 
+// `features` is what the client means to use. HELLO's payload and its handshake framing never
+// change, so a peer on any version can read it and refuse cleanly
 #[derive(Debug, Constructor, Clone, Copy)]
-pub struct StartRequest {
-    pub ob_id: u16,
+pub struct HelloRequest {
+    pub version: u16,
+    pub features: u32,
+}
+
+impl HelloRequest {
+    pub fn compatible(&self) -> bool {
+        self.version == PROTOCOL_VERSION && self.features & !FEATURES == 0
+    }
 }
 
 #[derive(Debug, Constructor, Clone, Copy)]
@@ -107,24 +503,106 @@ pub struct LevelViewRequest {
     pub ob_id: u16,
 }
 
-pub fn write_request(stream: &mut UnixStream, typ: &OBReqType, req: &OBRequest) -> Result<()> {
-    stream.write(&[typ.to_u8()])?;
-    stream.write_all(unsafe { any_as_u8_slice::<OBRequest>(req) })?;
-    Ok(())
+#[derive(Debug, Constructor, Clone, Copy)]
+pub struct StatusRequest {
+    pub oid: OrderId,
+    pub ob_id: u16,
+}
+
+// every order resting on the book, answered with a STATUS frame each
+#[derive(Debug, Constructor, Clone, Copy)]
+pub struct OrdersRequest {
+    pub ob_id: u16,
 }
 
-pub fn read_request(stream: &mut UnixStream) -> Result<OBRequestWrapper> {
-    let mut char_buf = [0u8; 1];
-    stream.read_exact(&mut char_buf)?;
-    let mut union_buf = [0u8; mem::size_of::<OBRequest>()];
-    stream.read_exact(&mut union_buf)?;
+message! {
+    AddRequest { qty, price, ob_id, tif, post_only, expires_at }
+    MarketRequest { qty, limit, ob_id }
+    TickRequest { now, ob_id }
+    AmendRequest { oid, qty, price, ob_id }
+    CancelRequest { oid, ob_id }
+    ReduceRequest { oid, qty, ob_id }
+    FlushRequest { ob_id }
+    HelloRequest { version, features }
+    LevelViewRequest { ob_id }
+    StatusRequest { oid, ob_id }
+    OrdersRequest { ob_id }
+}
+
+// the whole frame, header included
+pub fn encode_request(corr: u32, typ: &OBReqType, req: &OBRequest) -> Vec<u8> {
+    let tag = typ.to_u8();
+    unsafe {
+        match typ {
+            OBReqType::ADD => frame(tag, corr, &req.add),
+            OBReqType::CANCEL => frame(tag, corr, &req.cancel),
+            OBReqType::REDUCE => frame(tag, corr, &req.reduce),
+            OBReqType::FLUSH => frame(tag, corr, &req.flush),
+            OBReqType::HELLO => frame(tag, corr, &req.hello),
+            OBReqType::LEVELVIEW => frame(tag, corr, &req.level_view),
+            OBReqType::STATUS => frame(tag, corr, &req.status),
+            OBReqType::MARKET => frame(tag, corr, &req.market),
+            OBReqType::TICK => frame(tag, corr, &req.tick),
+            OBReqType::AMEND => frame(tag, corr, &req.amend),
+            OBReqType::ORDERS => frame(tag, corr, &req.orders),
+            OBReqType::UNREACHABLE => unreachable!("not a request"),
+        }
+    }
+}
 
-    let union = unsafe { u8_slice_to_struct::<OBRequest>(&union_buf) };
+pub fn write_request<S: Write + ?Sized>(stream: &mut S, corr: u32, typ: &OBReqType, req: &OBRequest) -> Result<()> {
+    stream.write_all(&encode_request(corr, typ, req))
+}
 
-    Ok(OBRequestWrapper {
-        typ: OBReqType::from_u8(char_buf[0]),
-        req: union,
-    })
+// the HELLO that opens a connection, in handshake framing
+pub fn encode_hello(hello: &HelloRequest) -> Vec<u8> {
+    hello_frame(frame(OBReqType::HELLO.to_u8(), 0, hello))
+}
+
+// checks a request's fields as it decodes them; `books` is how many books the engine runs
+pub fn decode_request(frame: &Frame, books: u16) -> std::result::Result<OBRequestWrapper, ProtocolError> {
+    let typ = OBReqType::from_u8(frame.tag).ok_or(ProtocolError::UnknownTag)?;
+    let payload = &frame.payload[..];
+
+    let req = match typ {
+        OBReqType::ADD => OBRequest { add: AddRequest::decode(payload)? },
+        OBReqType::CANCEL => OBRequest { cancel: CancelRequest::decode(payload)? },
+        OBReqType::REDUCE => OBRequest { reduce: ReduceRequest::decode(payload)? },
+        OBReqType::FLUSH => OBRequest { flush: FlushRequest::decode(payload)? },
+        OBReqType::HELLO => OBRequest { hello: HelloRequest::decode(payload)? },
+        OBReqType::LEVELVIEW => OBRequest { level_view: LevelViewRequest::decode(payload)? },
+        OBReqType::STATUS => OBRequest { status: StatusRequest::decode(payload)? },
+        OBReqType::MARKET => OBRequest { market: MarketRequest::decode(payload)? },
+        OBReqType::TICK => OBRequest { tick: TickRequest::decode(payload)? },
+        OBReqType::AMEND => OBRequest { amend: AmendRequest::decode(payload)? },
+        OBReqType::ORDERS => OBRequest { orders: OrdersRequest::decode(payload)? },
+        OBReqType::UNREACHABLE => return Err(ProtocolError::UnknownTag),
+    };
+    let req = OBRequestWrapper {
+        typ: typ,
+        req: req,
+    };
+
+    unsafe {
+        match typ {
+            OBReqType::ADD => check_price(req.req.add.price)?,
+            OBReqType::MARKET => check_price(req.req.market.limit)?,
+            OBReqType::AMEND => check_price(req.req.amend.price)?,
+            _ => (),
+        }
+    }
+
+    match req.ob_id() {
+        // HELLO isn't for a book, the server deals with it before anything is routed
+        None if matches!(typ, OBReqType::HELLO) => Ok(req),
+        None => Err(ProtocolError::Unsupported),
+        Some(ob_id) if ob_id >= books => Err(ProtocolError::UnknownBook),
+        Some(_) => Ok(req),
+    }
+}
+
+pub fn read_request<S: Read + ?Sized>(stream: &mut S, books: u16) -> Result<std::result::Result<OBRequestWrapper, ProtocolError>> {
+    Ok(read_frame(stream)?.and_then(|frame| decode_request(&frame, books)))
 }
 
 // --------------------------
@@ -136,14 +614,40 @@ pub enum OBRespType {
     PRICE = b'$',
     DELIM = b'#',
     LEVELVIEW = b'V',
+    ERROR = b'E',
+    STATUS = b'Q',
+    KILL = b'K',
+    REJECT = b'R',
+    MARKET = b'M',
+    EXPIRED = b'T',
+    AMEND = b'U',
+    PROTOCOL = b'P',
+    HELLO = b'H',
 }
 
 impl OBRespType {
     pub fn to_u8(&self) -> u8 {
         *self as u8
     }
-    pub fn from_u8(v: u8) -> Self {
-        unsafe { std::mem::transmute(v) }
+    // None for anything that isn't a response tag
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            b'A' => Some(OBRespType::ADD),
+            b'X' => Some(OBRespType::EXECUTE),
+            b'$' => Some(OBRespType::PRICE),
+            b'#' => Some(OBRespType::DELIM),
+            b'V' => Some(OBRespType::LEVELVIEW),
+            b'E' => Some(OBRespType::ERROR),
+            b'Q' => Some(OBRespType::STATUS),
+            b'K' => Some(OBRespType::KILL),
+            b'R' => Some(OBRespType::REJECT),
+            b'M' => Some(OBRespType::MARKET),
+            b'T' => Some(OBRespType::EXPIRED),
+            b'U' => Some(OBRespType::AMEND),
+            b'P' => Some(OBRespType::PROTOCOL),
+            b'H' => Some(OBRespType::HELLO),
+            _ => None,
+        }
     }
 }
 
@@ -161,29 +665,56 @@ impl std::fmt::Debug for OBResponseWrapper {
             OBRespType::PRICE => unsafe { self.resp.price.fmt(f) },
             OBRespType::DELIM => f.write_str("end of transmission"),
             OBRespType::LEVELVIEW => f.write_str("level view"),
+            OBRespType::ERROR => unsafe { self.resp.error.fmt(f) },
+            OBRespType::STATUS => unsafe { self.resp.status.fmt(f) },
+            OBRespType::KILL => unsafe { self.resp.kill.fmt(f) },
+            OBRespType::REJECT => unsafe { self.resp.reject.fmt(f) },
+            OBRespType::MARKET => unsafe { self.resp.market.fmt(f) },
+            OBRespType::EXPIRED => unsafe { self.resp.expired.fmt(f) },
+            OBRespType::AMEND => unsafe { self.resp.amend.fmt(f) },
+            OBRespType::PROTOCOL => unsafe { self.resp.protocol.fmt(f) },
+            OBRespType::HELLO => unsafe { self.resp.hello.fmt(f) },
         }
     }
 }
 
-#[derive(Clone, Copy)]
+impl Drop for OBResponseWrapper {
+    fn drop(&mut self) {
+        if let OBRespType::LEVELVIEW = self.typ {
+            unsafe { ManuallyDrop::drop(&mut self.resp.view) }
+        }
+    }
+}
+
+// the level view is boxed so the variants the matching path returns stay a few words wide;
+// the wrapper owns the box and drops it when typ is LEVELVIEW
 #[repr(C)]
 pub union OBResponse {
     pub add: AddResponse,
     pub execute: ExecuteResponse,
     pub price: PriceLevelResponse,
-    pub view: PriceViewResponse,
+    pub view: ManuallyDrop<Box<PriceViewResponse>>,
     pub end: DelimResponse,
+    pub error: ErrorResponse,
+    pub status: StatusResponse,
+    pub kill: KillResponse,
+    pub reject: RejectResponse,
+    pub market: MarketResponse,
+    pub expired: ExpiredResponse,
+    pub amend: AmendResponse,
+    pub protocol: ProtocolErrorResponse,
+    pub hello: HelloResponse,
 }
 
 #[derive(Debug, Constructor, Clone, Copy,Serialize)]
 pub struct AddResponse {
-    pub oid: usize,
+    pub oid: OrderId,
     pub qty: u64,
 }
 
 #[derive(Debug, Constructor, Clone, Copy)]
 pub struct ExecuteResponse {
-    pub executed_oid: usize,
+    pub executed_oid: OrderId,
     pub qty: u64,
 }
 
@@ -196,12 +727,93 @@ pub struct PriceLevelResponse {
 #[derive(Debug, Constructor, Clone, Copy)]
 pub struct DelimResponse {}
 
+#[derive(Debug, Constructor, Clone, Copy)]
+pub struct ErrorResponse {
+    pub err: BookError,
+}
+
+// a frame the engine couldn't decode, sent back in place of the normal reply
+#[derive(Debug, Constructor, Clone, Copy)]
+pub struct ProtocolErrorResponse {
+    pub err: ProtocolError,
+}
+
+// the engine accepted the connection; `books` is how many books it runs
+#[derive(Debug, Constructor, Clone, Copy)]
+pub struct HelloResponse {
+    pub version: u16,
+    pub features: u32,
+    pub books: u16,
+}
+
+// qty of an incoming order that was discarded instead of resting
+#[derive(Debug, Constructor, Clone, Copy)]
+pub struct KillResponse {
+    pub qty: u64,
+}
+
+// post-only order that would have crossed; nothing executed and nothing rests
+#[derive(Debug, Constructor, Clone, Copy)]
+pub struct RejectResponse {
+    pub qty: u64,
+}
+
+// GTT order the book clock cancelled; qty is what was still resting
+#[derive(Debug, Constructor, Clone, Copy)]
+pub struct ExpiredResponse {
+    pub oid: OrderId,
+    pub qty: u64,
+}
+
+// new_oid equals oid when the order kept its place; qty is what rests under new_oid,
+// zero (and new_oid OrderId::MAX) when the re-match filled or killed everything. pulled is
+// what came off the old order: the shrink when it kept its place, all of it otherwise
+#[derive(Debug, Constructor, Clone, Copy, Serialize)]
+pub struct AmendResponse {
+    pub oid: OrderId,
+    pub new_oid: OrderId,
+    pub qty: u64,
+    pub pulled: u64,
+}
+
+// summary of a market order; notional is the sum of qty * price over every fill
+#[derive(Debug, Constructor, Clone, Copy, Serialize)]
+pub struct MarketResponse {
+    pub filled: u64,
+    pub notional: u64,
+    pub avg_price: f64,
+}
+
+impl MarketResponse {
+    pub fn from_fills(filled: u64, notional: u64) -> Self {
+        MarketResponse {
+            filled: filled,
+            notional: notional,
+            avg_price: if filled == 0 { 0.0 } else { notional as f64 / filled as f64 },
+        }
+    }
+}
+
+#[derive(Debug, Constructor, Clone, Copy, Serialize)]
+pub struct StatusResponse {
+    pub oid: OrderId,
+    pub status: OrderStatus,
+    pub qty: u64,
+}
+
 type PriceViewResponseArray = [u64; 200];
 #[derive(Debug, Constructor, Clone, Copy)]
 pub struct PriceViewResponse {
     pub prices: PriceViewResponseArray,
 }
 
+impl PriceViewResponse {
+    // what OBResponse::view holds; only an OBResponseWrapper typed LEVELVIEW frees it
+    pub fn boxed(self) -> ManuallyDrop<Box<Self>> {
+        ManuallyDrop::new(Box::new(self))
+    }
+}
+
 impl PriceLevelResponse {
     pub fn from_pair(pair: (i8, i64)) -> Self {
         PriceLevelResponse {
@@ -211,13 +823,14 @@ impl PriceLevelResponse {
     }
 }
 
-pub fn write_response_vec(stream: &mut UnixStream, resps: Vec<OBResponseWrapper>) -> Result<()> {
+pub fn write_response_vec<S: Write + ?Sized>(stream: &mut S, corr: u32, resps: Vec<OBResponseWrapper>) -> Result<()> {
     for resp in resps.iter() {
-        write_response(stream, &resp.typ, &resp.resp)?;
+        write_response(stream, corr, &resp.typ, &resp.resp)?;
     }
 
     write_response(
         stream,
+        corr,
         &OBRespType::DELIM,
         &OBResponse {
             end: DelimResponse {},
@@ -226,28 +839,104 @@ pub fn write_response_vec(stream: &mut UnixStream, resps: Vec<OBResponseWrapper>
     Ok(())
 }
 
-pub fn write_response(stream: &mut UnixStream, typ: &OBRespType, data: &OBResponse) -> Result<()> {
-    let u8_slice = unsafe { any_as_u8_slice::<OBResponse>(data) };
-    stream.write(&[typ.to_u8()])?;
-    stream.write_all(&u8_slice)?;
-    Ok(())
+message! {
+    AddResponse { oid, qty }
+    ExecuteResponse { executed_oid, qty }
+    PriceLevelResponse { price, delta }
+    ErrorResponse { err }
+    ProtocolErrorResponse { err }
+    HelloResponse { version, features, books }
+    KillResponse { qty }
+    RejectResponse { qty }
+    ExpiredResponse { oid, qty }
+    AmendResponse { oid, new_oid, qty, pulled }
+    MarketResponse { filled, notional, avg_price }
+    StatusResponse { oid, status, qty }
+    PriceViewResponse { prices }
 }
 
-pub fn read_response(stream: &mut UnixStream) -> Result<OBResponseWrapper> {
-    let mut char_buf = [0u8; 1];
-    stream.read_exact(&mut char_buf)?;
-    let mut union_buf = [0u8; mem::size_of::<OBResponse>()];
-    stream.read_exact(&mut union_buf)?;
+impl Message for DelimResponse {
+    fn encode(&self, _: &mut Vec<u8>) {}
+    fn decode(payload: &[u8]) -> std::result::Result<Self, ProtocolError> {
+        if payload.is_empty() {
+            Ok(DelimResponse {})
+        } else {
+            Err(ProtocolError::BadLength)
+        }
+    }
+}
 
-    let union = unsafe { u8_slice_to_struct::<OBResponse>(&union_buf) };
+// the whole frame, header included
+pub fn encode_response(corr: u32, typ: &OBRespType, data: &OBResponse) -> Vec<u8> {
+    let tag = typ.to_u8();
+    unsafe {
+        match typ {
+            OBRespType::ADD => frame(tag, corr, &data.add),
+            OBRespType::EXECUTE => frame(tag, corr, &data.execute),
+            OBRespType::PRICE => frame(tag, corr, &data.price),
+            OBRespType::DELIM => frame(tag, corr, &data.end),
+            OBRespType::LEVELVIEW => frame(tag, corr, &**data.view),
+            OBRespType::ERROR => frame(tag, corr, &data.error),
+            OBRespType::STATUS => frame(tag, corr, &data.status),
+            OBRespType::KILL => frame(tag, corr, &data.kill),
+            OBRespType::REJECT => frame(tag, corr, &data.reject),
+            OBRespType::MARKET => frame(tag, corr, &data.market),
+            OBRespType::EXPIRED => frame(tag, corr, &data.expired),
+            OBRespType::AMEND => frame(tag, corr, &data.amend),
+            OBRespType::PROTOCOL => frame(tag, corr, &data.protocol),
+            OBRespType::HELLO => frame(tag, corr, &data.hello),
+        }
+    }
+}
+
+pub fn write_response<S: Write + ?Sized>(stream: &mut S, corr: u32, typ: &OBRespType, data: &OBResponse) -> Result<()> {
+    stream.write_all(&encode_response(corr, typ, data))
+}
+
+// the engine's answer to the opening HELLO, a HELLO or a PROTOCOL refusal, in handshake framing
+pub fn encode_hello_response(typ: &OBRespType, data: &OBResponse) -> Vec<u8> {
+    hello_frame(encode_response(0, typ, data))
+}
+
+pub fn decode_response(frame: &Frame) -> std::result::Result<OBResponseWrapper, ProtocolError> {
+    let typ = OBRespType::from_u8(frame.tag).ok_or(ProtocolError::UnknownTag)?;
+    let payload = &frame.payload[..];
+
+    let resp = match typ {
+        OBRespType::ADD => OBResponse { add: AddResponse::decode(payload)? },
+        OBRespType::EXECUTE => OBResponse { execute: ExecuteResponse::decode(payload)? },
+        OBRespType::PRICE => OBResponse { price: PriceLevelResponse::decode(payload)? },
+        OBRespType::DELIM => OBResponse { end: DelimResponse::decode(payload)? },
+        OBRespType::LEVELVIEW => OBResponse { view: PriceViewResponse::decode(payload)?.boxed() },
+        OBRespType::ERROR => OBResponse { error: ErrorResponse::decode(payload)? },
+        OBRespType::STATUS => OBResponse { status: StatusResponse::decode(payload)? },
+        OBRespType::KILL => OBResponse { kill: KillResponse::decode(payload)? },
+        OBRespType::REJECT => OBResponse { reject: RejectResponse::decode(payload)? },
+        OBRespType::MARKET => OBResponse { market: MarketResponse::decode(payload)? },
+        OBRespType::EXPIRED => OBResponse { expired: ExpiredResponse::decode(payload)? },
+        OBRespType::AMEND => OBResponse { amend: AmendResponse::decode(payload)? },
+        OBRespType::PROTOCOL => OBResponse { protocol: ProtocolErrorResponse::decode(payload)? },
+        OBRespType::HELLO => OBResponse { hello: HelloResponse::decode(payload)? },
+    };
 
     Ok(OBResponseWrapper {
-        typ: OBRespType::from_u8(char_buf[0]),
-        resp: union,
+        typ: typ,
+        resp: resp,
     })
 }
 
-pub fn read_response_vec(stream: &mut UnixStream) -> Result<Vec<OBResponseWrapper>> {
+// a response that doesn't decode comes back as InvalidData
+pub fn read_response<S: Read + ?Sized>(stream: &mut S) -> Result<OBResponseWrapper> {
+    let frame = read_frame(stream)??;
+    Ok(decode_response(&frame)?)
+}
+
+pub fn read_hello_response<S: Read + ?Sized>(stream: &mut S) -> Result<OBResponseWrapper> {
+    let frame = read_hello_frame(stream)??;
+    Ok(decode_response(&frame)?)
+}
+
+pub fn read_response_vec<S: Read + ?Sized>(stream: &mut S) -> Result<Vec<OBResponseWrapper>> {
     let mut ret: Vec<OBResponseWrapper> = Vec::new();
 
     loop {
@@ -259,3 +948,270 @@ pub fn read_response_vec(stream: &mut UnixStream) -> Result<Vec<OBResponseWrappe
     }
     Ok(ret)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // book 1, generation 2, slot 7 and book 1, generation 3, slot 8
+    const OID: OrderId = 0x0001_0002_0000_0007;
+    const OID_BYTES: [u8; 8] = [7, 0, 0, 0, 2, 0, 1, 0];
+    const NEW_OID: OrderId = 0x0001_0003_0000_0008;
+    const NEW_OID_BYTES: [u8; 8] = [8, 0, 0, 0, 3, 0, 1, 0];
+    const CORR: u32 = 0x0403_0201;
+    const CORR_BYTES: [u8; 4] = [1, 2, 3, 4];
+
+    fn le(v: u64) -> [u8; 8] {
+        v.to_le_bytes()
+    }
+
+    fn parse(bytes: &[u8]) -> Frame {
+        let mut stream = bytes;
+        let frame = read_frame(&mut stream).unwrap().unwrap();
+        assert!(stream.is_empty(), "frame didn't use every byte");
+        frame
+    }
+
+    // encodes to exactly `golden` and decodes back to the same frame
+    fn golden_request(typ: OBReqType, req: OBRequest, golden: &[&[u8]]) {
+        let golden = golden.concat();
+        assert!(encode_request(CORR, &typ, &req) == golden, "{:?}", OBRequestWrapper { req: req, typ: typ });
+        let frame = parse(&golden);
+        assert!(frame.corr == CORR);
+        let decoded = decode_request(&frame, 2).unwrap();
+        assert!(encode_request(CORR, &decoded.typ, &decoded.req) == golden);
+    }
+
+    fn golden_response(typ: OBRespType, resp: OBResponse, golden: &[&[u8]]) {
+        let golden = golden.concat();
+        // wrapped so a boxed level view is freed
+        let resp = OBResponseWrapper { resp: resp, typ: typ };
+        assert!(encode_response(CORR, &resp.typ, &resp.resp) == golden, "{:?}", resp);
+        let frame = parse(&golden);
+        assert!(frame.corr == CORR);
+        let decoded = decode_response(&frame).unwrap();
+        assert!(encode_response(CORR, &decoded.typ, &decoded.resp) == golden);
+    }
+
+    #[test]
+    fn test_golden_requests() {
+        golden_request(OBReqType::ADD, OBRequest { add: AddRequest::new(5, -30, 1, TimeInForce::GTT, true, 1000) }, &[
+            &[b'A', 21, 0],
+            &CORR_BYTES,
+            &le(5),
+            &[0xe2],
+            &[1, 0],
+            &[b'T', 1],
+            &le(1000),
+        ]);
+        golden_request(OBReqType::MARKET, OBRequest { market: MarketRequest::new(12, 40, 1) }, &[
+            &[b'M', 11, 0],
+            &CORR_BYTES,
+            &le(12),
+            &[40],
+            &[1, 0],
+        ]);
+        golden_request(OBReqType::TICK, OBRequest { tick: TickRequest::new(1000, 1) }, &[
+            &[b'T', 10, 0],
+            &CORR_BYTES,
+            &le(1000),
+            &[1, 0],
+        ]);
+        golden_request(OBReqType::AMEND, OBRequest { amend: AmendRequest::new(OID, 3, -45, 1) }, &[
+            &[b'U', 19, 0],
+            &CORR_BYTES,
+            &OID_BYTES,
+            &le(3),
+            &[0xd3],
+            &[1, 0],
+        ]);
+        golden_request(OBReqType::CANCEL, OBRequest { cancel: CancelRequest::new(OID, 1) }, &[
+            &[b'C', 10, 0],
+            &CORR_BYTES,
+            &OID_BYTES,
+            &[1, 0],
+        ]);
+        golden_request(OBReqType::REDUCE, OBRequest { reduce: ReduceRequest::new(OID, 2, 1) }, &[
+            &[b'R', 18, 0],
+            &CORR_BYTES,
+            &OID_BYTES,
+            &le(2),
+            &[1, 0],
+        ]);
+        golden_request(OBReqType::STATUS, OBRequest { status: StatusRequest::new(OID, 1) }, &[
+            &[b'Q', 10, 0],
+            &CORR_BYTES,
+            &OID_BYTES,
+            &[1, 0],
+        ]);
+        golden_request(OBReqType::FLUSH, OBRequest { flush: FlushRequest::new(1) }, &[&[b'F', 2, 0], &CORR_BYTES, &[1, 0]]);
+        golden_request(OBReqType::LEVELVIEW, OBRequest { level_view: LevelViewRequest::new(1) }, &[&[b'V', 2, 0], &CORR_BYTES, &[1, 0]]);
+        golden_request(OBReqType::ORDERS, OBRequest { orders: OrdersRequest::new(1) }, &[&[b'O', 2, 0], &CORR_BYTES, &[1, 0]]);
+    }
+
+    #[test]
+    fn test_golden_responses() {
+        golden_response(OBRespType::ADD, OBResponse { add: AddResponse::new(OID, 5) }, &[
+            &[b'A', 16, 0],
+            &CORR_BYTES,
+            &OID_BYTES,
+            &le(5),
+        ]);
+        golden_response(OBRespType::EXECUTE, OBResponse { execute: ExecuteResponse::new(OID, 4) }, &[
+            &[b'X', 16, 0],
+            &CORR_BYTES,
+            &OID_BYTES,
+            &le(4),
+        ]);
+        golden_response(OBRespType::PRICE, OBResponse { price: PriceLevelResponse::new(-30, -5) }, &[
+            &[b'$', 9, 0],
+            &CORR_BYTES,
+            &[0xe2],
+            &[0xfb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        ]);
+        golden_response(OBRespType::DELIM, OBResponse { end: DelimResponse {} }, &[&[b'#', 0, 0], &CORR_BYTES]);
+        golden_response(OBRespType::ERROR, OBResponse { error: ErrorResponse::new(BookError::BookFull) }, &[&[b'E', 1, 0], &CORR_BYTES, b"B"]);
+        golden_response(OBRespType::STATUS, OBResponse { status: StatusResponse::new(OID, OrderStatus::PartiallyFilled, 3) }, &[
+            &[b'Q', 17, 0],
+            &CORR_BYTES,
+            &OID_BYTES,
+            b"P",
+            &le(3),
+        ]);
+        golden_response(OBRespType::KILL, OBResponse { kill: KillResponse::new(6) }, &[&[b'K', 8, 0], &CORR_BYTES, &le(6)]);
+        golden_response(OBRespType::REJECT, OBResponse { reject: RejectResponse::new(6) }, &[&[b'R', 8, 0], &CORR_BYTES, &le(6)]);
+        // avg_price 25.0 is 0x4039000000000000
+        golden_response(OBRespType::MARKET, OBResponse { market: MarketResponse::from_fills(4, 100) }, &[
+            &[b'M', 24, 0],
+            &CORR_BYTES,
+            &le(4),
+            &le(100),
+            &[0, 0, 0, 0, 0, 0, 0x39, 0x40],
+        ]);
+        golden_response(OBRespType::EXPIRED, OBResponse { expired: ExpiredResponse::new(OID, 2) }, &[
+            &[b'T', 16, 0],
+            &CORR_BYTES,
+            &OID_BYTES,
+            &le(2),
+        ]);
+        golden_response(OBRespType::AMEND, OBResponse { amend: AmendResponse::new(OID, NEW_OID, 3, 5) }, &[
+            &[b'U', 32, 0],
+            &CORR_BYTES,
+            &OID_BYTES,
+            &NEW_OID_BYTES,
+            &le(3),
+            &le(5),
+        ]);
+        golden_response(OBRespType::PROTOCOL, OBResponse { protocol: ProtocolErrorResponse::new(ProtocolError::PriceOutOfRange) }, &[
+            &[b'P', 1, 0],
+            &CORR_BYTES,
+            b"P",
+        ]);
+
+        // 200 u64s, 1600 = 0x640 bytes
+        let mut prices = [0u64; 200];
+        prices[30] = 5;
+        prices[140] = 7;
+        let mut levels = [0u8; 1600];
+        levels[30 * 8] = 5;
+        levels[140 * 8] = 7;
+        golden_response(OBRespType::LEVELVIEW, OBResponse { view: PriceViewResponse::new(prices).boxed() }, &[
+            &[b'V', 0x40, 0x06],
+            &CORR_BYTES,
+            &levels,
+        ]);
+    }
+
+    // every fill pushes a couple of these, so the level view must not set their size
+    #[test]
+    fn test_response_stays_small() {
+        assert!(std::mem::size_of::<OBResponseWrapper>() <= 40);
+    }
+
+    // the handshake has no corr, and these bytes are what a version 1 peer sends and reads
+    #[test]
+    fn test_golden_handshake() {
+        let golden: &[u8] = &[b'S', 6, 0, 1, 0, 0x06, 0, 0, 0];
+        assert!(encode_hello(&HelloRequest::new(1, FEATURE_AMEND | FEATURE_MARKET)) == golden);
+        let mut stream = golden;
+        let frame = read_hello_frame(&mut stream).unwrap().unwrap();
+        assert!(stream.is_empty() && frame.corr == 0);
+        let hello = unsafe { decode_request(&frame, 2).unwrap().req.hello };
+        assert!(hello.version == 1 && hello.features == FEATURE_AMEND | FEATURE_MARKET);
+
+        let golden: &[u8] = &[b'H', 8, 0, 1, 0, 0x09, 0, 0, 0, 2, 0];
+        let hello = HelloResponse::new(1, FEATURE_TIME_IN_FORCE | FEATURE_EXPIRY, 2);
+        assert!(encode_hello_response(&OBRespType::HELLO, &OBResponse { hello: hello }) == golden);
+        let mut stream = golden;
+        let hello = unsafe { read_hello_response(&mut stream).unwrap().resp.hello };
+        assert!(stream.is_empty() && hello.version == 1 && hello.books == 2);
+
+        let refusal = OBResponse { protocol: ProtocolErrorResponse::new(ProtocolError::Incompatible) };
+        assert!(encode_hello_response(&OBRespType::PROTOCOL, &refusal) == [b'P', 1, 0, b'V']);
+    }
+
+    fn add_frame(price: i8, ob_id: u16) -> Frame {
+        parse(&encode_request(0, &OBReqType::ADD, &OBRequest { add: AddRequest::new(5, price, ob_id, TimeInForce::GTC, false, 0) }))
+    }
+
+    #[test]
+    fn test_decode_rejects_bad_frames() {
+        let mut bad_tag = add_frame(-30, 0);
+        bad_tag.tag = b'z';
+        assert!(matches!(decode_request(&bad_tag, 2), Err(ProtocolError::UnknownTag)));
+
+        // tif and post_only sit after qty, price and ob_id
+        let mut bad_tif = add_frame(-30, 0);
+        bad_tif.payload[11] = 0xff;
+        assert!(matches!(decode_request(&bad_tif, 2), Err(ProtocolError::BadField)));
+
+        let mut bad_bool = add_frame(-30, 0);
+        bad_bool.payload[12] = 2;
+        assert!(matches!(decode_request(&bad_bool, 2), Err(ProtocolError::BadField)));
+
+        let mut short = add_frame(-30, 0);
+        short.payload.pop();
+        assert!(matches!(decode_request(&short, 2), Err(ProtocolError::BadLength)));
+        let mut long = add_frame(-30, 0);
+        long.payload.push(0);
+        assert!(matches!(decode_request(&long, 2), Err(ProtocolError::BadLength)));
+
+        for price in [100, -100, i8::MIN] {
+            assert!(matches!(decode_request(&add_frame(price, 0), 2), Err(ProtocolError::PriceOutOfRange)));
+        }
+        // the book's NO side starts at 0
+        for price in [-99, 0, 99] {
+            assert!(decode_request(&add_frame(price, 0), 2).is_ok());
+        }
+        let market = parse(&encode_request(0, &OBReqType::MARKET, &OBRequest { market: MarketRequest::new(5, 120, 0) }));
+        assert!(matches!(decode_request(&market, 2), Err(ProtocolError::PriceOutOfRange)));
+
+        assert!(matches!(decode_request(&add_frame(-30, 2), 2), Err(ProtocolError::UnknownBook)));
+        let cancel = parse(&encode_request(0, &OBReqType::CANCEL, &OBRequest { cancel: CancelRequest::new(0, 7) }));
+        assert!(matches!(decode_request(&cancel, 2), Err(ProtocolError::UnknownBook)));
+
+        // HELLO decodes but isn't for any book
+        let mut hello: &[u8] = &encode_hello(&HelloRequest::new(PROTOCOL_VERSION, FEATURES));
+        let hello = read_hello_frame(&mut hello).unwrap().unwrap();
+        assert!(decode_request(&hello, 2).unwrap().ob_id().is_none());
+    }
+
+    #[test]
+    fn test_read_request_truncated() {
+        let whole = encode_request(0, &OBReqType::CANCEL, &OBRequest { cancel: CancelRequest::new(OID, 0) });
+        let mut empty: &[u8] = &[];
+        assert!(read_request(&mut empty, 2).unwrap_err().kind() == std::io::ErrorKind::UnexpectedEof);
+        for cut in [1, 2, HEADER, whole.len() - 1] {
+            let mut cut: &[u8] = &whole[..cut];
+            assert!(matches!(read_request(&mut cut, 2).unwrap(), Err(ProtocolError::Truncated)));
+        }
+    }
+
+    #[test]
+    fn test_hello_compatible() {
+        assert!(HelloRequest::new(PROTOCOL_VERSION, FEATURES).compatible());
+        assert!(HelloRequest::new(PROTOCOL_VERSION, 0).compatible());
+        assert!(!HelloRequest::new(PROTOCOL_VERSION + 1, FEATURES).compatible());
+        assert!(!HelloRequest::new(PROTOCOL_VERSION, FEATURES | 1 << 31).compatible());
+    }
+}
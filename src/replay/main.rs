@@ -0,0 +1,141 @@
+extern crate fast_book;
+
+use fast_book::comm::manager::Manager;
+use fast_book::comm::replay::*;
+use fast_book::comm::stream::{connect, handshake};
+
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Result, Write};
+
+// same as the engine, so oids and full books come out the way they did there
+const ORDER_SIZE: usize = 1000000;
+const BOOKS: u16 = 2;
+
+// mismatches shown before the rest are only counted
+const DIFF_LIMIT: usize = 20;
+
+const USAGE: &str = "usage: replay [--journal] [--snapshot FILE] [--engine ADDR] [--books N] [--capacity N] [--diff EXPECTED] FILE
+
+Runs a recorded journal (--journal, or any *.journal file) or an order script through the
+books and prints every request followed by what it produced.
+
+  --snapshot FILE   start from an engine snapshot, journal records it already has are skipped
+  --engine ADDR     send the requests to a running engine (socket path or tcp://host:port)
+                    instead of books in this process
+  --books N         books to run, and to check journal records against (default 2)
+  --capacity N      order slots per book (default the engine's)
+  --diff EXPECTED   compare against the output of an earlier run instead of printing it";
+
+struct Options {
+    path: String,
+    journal: bool,
+    snapshot: Option<String>,
+    engine: Option<String>,
+    books: u16,
+    capacity: usize,
+    diff: Option<String>,
+}
+
+fn usage(problem: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("{}\n\n{}", problem, USAGE))
+}
+
+fn options() -> Result<Options> {
+    let mut args = std::env::args().skip(1);
+    let mut options = Options {
+        path: String::new(),
+        journal: false,
+        snapshot: None,
+        engine: None,
+        books: BOOKS,
+        capacity: ORDER_SIZE,
+        diff: None,
+    };
+    let mut path = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| usage(&format!("{} needs a value", name)));
+        match arg.as_str() {
+            "--journal" => options.journal = true,
+            "--snapshot" => options.snapshot = Some(value("--snapshot")?),
+            "--engine" => options.engine = Some(value("--engine")?),
+            "--books" => options.books = value("--books")?.parse().map_err(|_| usage("--books must be a number"))?,
+            "--capacity" => options.capacity = value("--capacity")?.parse().map_err(|_| usage("--capacity must be a number"))?,
+            "--diff" => options.diff = Some(value("--diff")?),
+            "-h" | "--help" => return Err(usage("")),
+            _ if arg.starts_with("--") => return Err(usage(&format!("unknown option {}", arg))),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(usage("only one file can be replayed at a time")),
+        }
+    }
+
+    options.path = path.ok_or_else(|| usage("nothing to replay"))?;
+    options.journal |= options.path.ends_with(".journal");
+    if options.snapshot.is_some() && options.engine.is_some() {
+        return Err(usage("a snapshot can only be loaded into local books"));
+    }
+    Ok(options)
+}
+
+fn main() {
+    // diffs that don't match exit with 1, anything that stops the replay with 2
+    if let Err(e) = replay() {
+        eprintln!("replay: {}", e);
+        std::process::exit(2);
+    }
+}
+
+fn replay() -> Result<()> {
+    let options = options()?;
+
+    let (mut target, skip_through) = match (&options.engine, &options.snapshot) {
+        (Some(addr), _) => {
+            let mut stream = connect(addr)?;
+            let hello = handshake(&mut stream)?;
+            if hello.books < options.books {
+                return Err(Error::new(ErrorKind::Unsupported, format!("engine runs {} books, the replay needs {}", hello.books, options.books)));
+            }
+            (Target::Engine(stream), 0)
+        },
+        (None, Some(path)) => {
+            let (manager, seq) = Manager::restore(&std::fs::read(path)?, options.capacity)?;
+            if manager.book_size() != options.books as usize {
+                return Err(Error::new(ErrorKind::InvalidData, format!("{} holds {} books, not {}", path, manager.book_size(), options.books)));
+            }
+            (Target::Local(manager), seq)
+        },
+        (None, None) => (Target::Local(Manager::new(options.capacity, options.books)), 0),
+    };
+
+    let in_file = |e: Error| Error::new(e.kind(), format!("{}: {}", options.path, e));
+    let reqs = if options.journal {
+        journal_requests(BufReader::new(File::open(&options.path)?), options.books, skip_through).map_err(in_file)?
+    } else {
+        script_requests(&std::fs::read_to_string(&options.path)?, options.books).map_err(in_file)?
+    };
+    let lines = run(&mut target, &reqs)?;
+
+    match &options.diff {
+        Some(expected) => {
+            let (report, matched) = diff(&std::fs::read_to_string(expected)?, &lines, DIFF_LIMIT);
+            for line in report {
+                println!("{}", line);
+            }
+            if !matched {
+                std::process::exit(1);
+            }
+        },
+        None => {
+            let mut out = std::io::stdout().lock();
+            for line in lines {
+                match writeln!(out, "{}", line) {
+                    Ok(()) => (),
+                    // e.g. piped into head, nobody wants the rest
+                    Err(e) if e.kind() == ErrorKind::BrokenPipe => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        },
+    }
+    Ok(())
+}
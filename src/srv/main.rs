@@ -1,56 +1,84 @@
 extern crate fast_book;
 
-use fast_book::comm::urcp::*;
+use fast_book::comm::journal::Journal;
 use fast_book::comm::manager::*;
+use fast_book::comm::server::*;
 
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
+use std::net::TcpListener;
 use std::os::unix::net::*;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
+// order slots per book
 const ORDER_SIZE: usize = 1000000;
-const LEVEL_SIZE: usize = 200;
 const BOOKS: u16 = 2;
 
-const STREAM_ADDR: &'static str = "/tmp/fish.socket";
+const STREAM_ADDR: &str = "/tmp/fish.socket";
+// ENGINE_JOURNAL overrides; the books are rebuilt from it on every start
+const JOURNAL_PATH: &str = "/tmp/fish.journal";
+// ENGINE_SNAPSHOT overrides; the journal is replayed on top of it
+const SNAPSHOT_PATH: &str = "/tmp/fish.snapshot";
+// seconds between snapshots, ENGINE_SNAPSHOT_SECS overrides and 0 turns them off; each one
+// empties the journal
+const SNAPSHOT_SECS: u64 = 60;
 
+// worker threads for the books, ENGINE_THREADS overrides; defaults to one per book
+fn engine_threads() -> usize {
+    match std::env::var("ENGINE_THREADS") {
+        Ok(threads) => threads.parse().expect("ENGINE_THREADS must be a number"),
+        Err(_) => BOOKS as usize,
+    }
+}
 
 fn main() -> Result<()> {
-    let mut manager = Manager::new(ORDER_SIZE, LEVEL_SIZE, BOOKS);
+    let journal_path = std::env::var("ENGINE_JOURNAL").unwrap_or(String::from(JOURNAL_PATH));
+    let snapshot_path = std::env::var("ENGINE_SNAPSHOT").unwrap_or(String::from(SNAPSHOT_PATH));
 
-    let _ = std::fs::remove_file(STREAM_ADDR);
+    let (mut manager, seq) = match std::fs::read(&snapshot_path) {
+        Ok(bytes) => Manager::restore(&bytes, ORDER_SIZE)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => (Manager::new(ORDER_SIZE, BOOKS), 0),
+        Err(e) => return Err(e),
+    };
+    if manager.book_size() != BOOKS as usize {
+        return Err(Error::new(ErrorKind::InvalidData, format!(
+            "{} holds {} books, the engine runs {}", snapshot_path, manager.book_size(), BOOKS,
+        )));
+    }
+    let journal = Journal::replay(&journal_path, &mut manager, seq)?;
+    println!("restored to record {} from {}, replayed {} from {}", seq, snapshot_path, journal.next_seq() - 1 - seq, journal_path);
 
-    let server = UnixListener::bind(STREAM_ADDR)?;
+    let router = Arc::new(manager.spawn(engine_threads()).journaled(journal));
 
-    let mut listener = server.accept().unwrap().0;
-
-    listener.set_read_timeout(None)?;
-    listener.set_write_timeout(None)?;
-    listener.set_nonblocking(false)?;
-
-    loop {
-        let request = read_request(&mut listener)?;
-        println!("{:?}", request);
-        unsafe {
-            match request {
-                OBRequestWrapper { req: OBRequest { add: req }, typ: OBReqType::ADD } => {
-                    let response_vec = manager[req.ob_id as usize].match_order(req.qty, req.price);
-                    write_response_vec(&mut listener, response_vec)?;
-                },
-                OBRequestWrapper { req: OBRequest { cancel: req }, typ: OBReqType::CANCEL } => {
-                    let price_level_response = manager[req.ob_id as usize].delete(req.oid);
-                    write_response(&mut listener, &OBRespType::PRICE, &OBResponse { price: price_level_response })?;
-                },
-                OBRequestWrapper { req: OBRequest { reduce: req }, typ: OBReqType::REDUCE } => {
-                    let price_level_response = manager[req.ob_id as usize].reduce(req.oid, req.qty);
-                    write_response(&mut listener, &OBRespType::PRICE, &OBResponse { price: price_level_response })?;
-                },
-                OBRequestWrapper { req: OBRequest { flush: req }, typ: OBReqType::FLUSH } => {
-                    manager[req.ob_id as usize].clear();
-                    write_response(&mut listener, &OBRespType::DELIM, &OBResponse { end: DelimResponse{}})?;
-                },
-                _ => break
-            };
-        };
+    let snapshot_secs = match std::env::var("ENGINE_SNAPSHOT_SECS") {
+        Ok(secs) => secs.parse().expect("ENGINE_SNAPSHOT_SECS must be a number"),
+        Err(_) => SNAPSHOT_SECS,
+    };
+    if snapshot_secs > 0 {
+        let snapshots = Arc::clone(&router);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(snapshot_secs));
+            match snapshots.snapshot(&snapshot_path) {
+                Ok(seq) => println!("snapshot at record {}", seq),
+                Err(e) => println!("SNAPSHOT: {}", e),
+            }
+        });
+    } else {
+        println!("snapshots off, the journal only grows");
     }
 
-    unreachable!("malformatted request");
+    // ENGINE_TCP_ADDR (e.g. 0.0.0.0:7070) also serves URCP over tcp for clients on other hosts
+    if let Ok(addr) = std::env::var("ENGINE_TCP_ADDR") {
+        let listener = TcpListener::bind(&addr)?;
+        println!("listening on tcp://{}", listener.local_addr()?);
+        let router = Arc::clone(&router);
+        thread::spawn(move || accept_tcp(router, listener));
+    }
+
+    let _ = std::fs::remove_file(STREAM_ADDR);
+
+    let server = UnixListener::bind(STREAM_ADDR)?;
+
+    accept_unix(router, server)
 }
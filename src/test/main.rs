@@ -1,16 +1,23 @@
 extern crate fast_book;
 
 use fast_book::comm::urcp::*;
+use fast_book::comm::stream::{connect, handshake};
+use fast_book::book::book::TimeInForce;
 
 use std::io::Result;
-use std::os::unix::net::UnixStream;
 
-const STREAM_ADDR: &'static str = "/tmp/fish.socket";
+const STREAM_ADDR: &str = "/tmp/fish.socket";
 
 fn main() -> Result<()> {
-    let mut listener = UnixStream::connect(STREAM_ADDR)?;
+    // the engine address can be passed in, either a socket path or tcp://host:port
+    let addr = std::env::args().nth(1).unwrap_or(String::from(STREAM_ADDR));
+    let mut listener = connect(&addr)?;
+    println!("{:?}", handshake(&mut listener)?);
 
+    // one request at a time, so corr is only there to show in the engine's log
+    let mut corr: u32 = 0;
     loop {
+        corr += 1;
         let mut input = String::new();
 
         std::io::stdin().read_line(&mut input).unwrap();
@@ -24,12 +31,59 @@ fn main() -> Result<()> {
 
         match mast {
             'A' => {
-                debug_assert!(inputs.len() == 3);
+                debug_assert!(inputs.len() >= 3 && inputs.len() <= 5);
                 let qty = inputs[0].parse::<u64>().unwrap();
                 let price = inputs[1].parse::<i8>().unwrap();
                 let ob_id = inputs[2].parse::<u16>().unwrap();
-                let req = AddRequest::new(qty, price, ob_id);
-                write_request(&mut listener, &OBReqType::ADD, &OBRequest{ add: req })?;
+                // GTT is spelled T<expiry>
+                let (tif, expires_at) = match inputs.get(3) {
+                    Some(&"I") => (TimeInForce::IOC, 0),
+                    Some(&"F") => (TimeInForce::FOK, 0),
+                    Some(s) if s.starts_with('T') => (TimeInForce::GTT, s[1..].parse::<u64>().unwrap()),
+                    _ => (TimeInForce::GTC, 0),
+                };
+                let post_only = matches!(inputs.get(4), Some(&"P"));
+                let req = AddRequest::new(qty, price, ob_id, tif, post_only, expires_at);
+                write_request(&mut listener, corr, &OBReqType::ADD, &OBRequest{ add: req })?;
+                let response_vec = read_response_vec(&mut listener)?;
+
+                for response in response_vec.iter() {
+                    println!("{:?}", response);
+                }
+            },
+            'M' => {
+                debug_assert!(inputs.len() == 3);
+                let qty = inputs[0].parse::<u64>().unwrap();
+                let limit = inputs[1].parse::<i8>().unwrap();
+                let ob_id = inputs[2].parse::<u16>().unwrap();
+                let req = MarketRequest::new(qty, limit, ob_id);
+                write_request(&mut listener, corr, &OBReqType::MARKET, &OBRequest{ market: req })?;
+                let response_vec = read_response_vec(&mut listener)?;
+
+                for response in response_vec.iter() {
+                    println!("{:?}", response);
+                }
+            },
+            'T' => {
+                debug_assert!(inputs.len() == 2);
+                let now = inputs[0].parse::<u64>().unwrap();
+                let ob_id = inputs[1].parse::<u16>().unwrap();
+                let req = TickRequest::new(now, ob_id);
+                write_request(&mut listener, corr, &OBReqType::TICK, &OBRequest{ tick: req })?;
+                let response_vec = read_response_vec(&mut listener)?;
+
+                for response in response_vec.iter() {
+                    println!("{:?}", response);
+                }
+            },
+            'U' => {
+                debug_assert!(inputs.len() == 4);
+                let oid = inputs[0].parse::<u64>().unwrap();
+                let qty = inputs[1].parse::<u64>().unwrap();
+                let price = inputs[2].parse::<i8>().unwrap();
+                let ob_id = inputs[3].parse::<u16>().unwrap();
+                let req = AmendRequest::new(oid, qty, price, ob_id);
+                write_request(&mut listener, corr, &OBReqType::AMEND, &OBRequest{ amend: req })?;
                 let response_vec = read_response_vec(&mut listener)?;
 
                 for response in response_vec.iter() {
@@ -38,41 +92,67 @@ fn main() -> Result<()> {
             },
             'C' => {
                 debug_assert!(inputs.len() == 2);
-                let oid = inputs[0].parse::<usize>().unwrap();
+                let oid = inputs[0].parse::<u64>().unwrap();
                 let ob_id = inputs[1].parse::<u16>().unwrap();
                 let req = CancelRequest::new(oid, ob_id);
-                write_request(&mut listener, &OBReqType::CANCEL, &OBRequest{ cancel: req })?;
+                write_request(&mut listener, corr, &OBReqType::CANCEL, &OBRequest{ cancel: req })?;
                 let response = read_response(&mut listener)?;
                 println!("{:?}", response);
             },
             'R' => {
                 debug_assert!(inputs.len() == 3);
-                let oid = inputs[0].parse::<usize>().unwrap();
+                let oid = inputs[0].parse::<u64>().unwrap();
                 let qty = inputs[1].parse::<u64>().unwrap();
                 let ob_id = inputs[2].parse::<u16>().unwrap();
                 let req = ReduceRequest::new(oid, qty, ob_id);
-                write_request(&mut listener, &OBReqType::REDUCE, &OBRequest{ reduce: req })?;
+                write_request(&mut listener, corr, &OBReqType::REDUCE, &OBRequest{ reduce: req })?;
+                let response = read_response(&mut listener)?;
+                println!("{:?}", response);
+            },
+            'Q' => {
+                debug_assert!(inputs.len() == 2);
+                let oid = inputs[0].parse::<u64>().unwrap();
+                let ob_id = inputs[1].parse::<u16>().unwrap();
+                let req = StatusRequest::new(oid, ob_id);
+                write_request(&mut listener, corr, &OBReqType::STATUS, &OBRequest{ status: req })?;
                 let response = read_response(&mut listener)?;
                 println!("{:?}", response);
             },
+            'O' => {
+                debug_assert!(inputs.len() == 1);
+                let ob_id = inputs[0].parse::<u16>().unwrap();
+                let req = OrdersRequest::new(ob_id);
+                write_request(&mut listener, corr, &OBReqType::ORDERS, &OBRequest{ orders: req })?;
+                let response_vec = read_response_vec(&mut listener)?;
+
+                for response in response_vec.iter() {
+                    println!("{:?}", response);
+                }
+            },
             'F' => {
                 debug_assert!(inputs.len() == 1);
                 let ob_id = inputs[0].parse::<u16>().unwrap();
                 let req = FlushRequest::new(ob_id);
-                write_request(&mut listener, &OBReqType::FLUSH, &OBRequest{ flush: req })?;
+                write_request(&mut listener, corr, &OBReqType::FLUSH, &OBRequest{ flush: req })?;
                 let response = read_response(&mut listener)?;
                 println!("{:?}", response);
             },
-            'S' => {
+            'V' => {
                 debug_assert!(inputs.len() == 1);
                 let ob_id = inputs[0].parse::<u16>().unwrap();
-                let req = StartRequest::new(ob_id);
-                write_request(&mut listener, &OBReqType::START, &OBRequest{ start: req })?;
+                let req = LevelViewRequest::new(ob_id);
+                write_request(&mut listener, corr, &OBReqType::LEVELVIEW, &OBRequest{ level_view: req })?;
                 let response = read_response(&mut listener)?;
-                println!("{:?}", response);
+                let view = unsafe { &response.resp.view };
+                for (idx, qty) in view.prices.iter().enumerate().filter(|(_, qty)| **qty > 0) {
+                    if idx < 100 {
+                        println!("YES $0.{} @ {}", idx, qty);
+                    } else {
+                        println!("NO $0.{} @ {}", idx - 100, qty);
+                    }
+                }
             },
             _ => {
-                listener.shutdown(std::net::Shutdown::Both)?;
                 break;
             },
         }